@@ -1,33 +1,73 @@
 #include <stdio.h>
+#include <stdlib.h>
 #include <stdint.h>
+#include <inttypes.h>
 
 extern int64_t printchar(int64_t);
 extern int64_t printint(int64_t);
 extern int64_t printcharln(int64_t);
 extern int64_t printintln(int64_t);
-extern int64_t readchar();
+extern int64_t readchar(void);
 
 int64_t printint(int64_t c) {
-	printf("%d", c);
+	printf("%" PRId64, c);
 	return c;
 }
 int64_t printintln(int64_t c) {
-	printf("%d\n", c);
+	printf("%" PRId64 "\n", c);
 	return c;
 }
 
-//
+// chars are unicode scalar values, written out as utf-8
+static void pututf8(int64_t c, FILE *out) {
+	if (c < 0x80) {
+		fputc(c, out);
+	} else if (c < 0x800) {
+		fputc(0xC0 | (c >> 6), out);
+		fputc(0x80 | (c & 0x3F), out);
+	} else if (c < 0x10000) {
+		fputc(0xE0 | (c >> 12), out);
+		fputc(0x80 | ((c >> 6) & 0x3F), out);
+		fputc(0x80 | (c & 0x3F), out);
+	} else {
+		fputc(0xF0 | (c >> 18), out);
+		fputc(0x80 | ((c >> 12) & 0x3F), out);
+		fputc(0x80 | ((c >> 6) & 0x3F), out);
+		fputc(0x80 | (c & 0x3F), out);
+	}
+}
 int64_t printchar(int64_t c) {
-	printf("%c", (char)c);
+	pututf8(c, stdout);
 	return c;
 }
 int64_t printcharln(int64_t c) {
-	printf("%c\n", (char)c);
+	pututf8(c, stdout);
+	putchar('\n');
 	return c;
 }
 
-int64_t readchar() {
+int64_t readchar(void) {
 	char choice;
-	scanf(" %c", &choice);	
+	// -1 at the end of input, like the JIT and the interpreter
+	if (scanf(" %c", &choice) != 1) {
+		return -1;
+	}
 	return (int64_t)choice;
 }
+
+// file is a string: its length followed by that many chars
+static void panic_at(int64_t *file, int64_t line, int64_t column, const char *message) {
+	fflush(stdout);
+	fputs("panic at ", stderr);
+	for (int64_t i = 1; i <= file[0]; i++) {
+		pututf8(file[i], stderr);
+	}
+	fprintf(stderr, ":%" PRId64 ":%" PRId64 ": %s\n", line, column, message);
+	exit(101);
+}
+void moist_panic_overflow(int64_t *file, int64_t line, int64_t column) {
+	panic_at(file, line, column, "arithmetic overflow");
+}
+void moist_panic_divide_by_zero(int64_t *file, int64_t line, int64_t column) {
+	panic_at(file, line, column, "division by zero");
+}
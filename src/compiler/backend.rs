@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+
+use cranelift::codegen::{
+    isa::{self, OwnedTargetIsa},
+    settings::{self, Configurable},
+};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule, ObjectProduct};
+use target_lexicon::{BinaryFormat, PointerWidth, Triple};
+
+use super::{runtime, CompilerOptions, OptLevel};
+
+/// A module the compiler can lower functions into. Setting one up and turning
+/// it into something useful once every function is defined are the only parts
+/// that differ between backends.
+pub trait Backend: Module + Sized {
+    /// What the module turns into once it is finished.
+    type Output;
+
+    /// Whether code has to work wherever it is loaded.
+    const PIC: bool;
+
+    fn for_isa(isa: OwnedTargetIsa) -> Self;
+
+    /// Makes every function defined so far ready to use, while more can
+    /// still be defined.
+    fn flush(&mut self) -> Result<(), String>;
+
+    fn finalize(self, functions: &HashMap<String, FuncId>) -> Result<Self::Output, String>;
+}
+
+impl Backend for ObjectModule {
+    /// The finished object, which debug info can still be written into
+    /// before it is emitted as a relocatable file.
+    type Output = ObjectProduct;
+
+    const PIC: bool = true;
+
+    fn for_isa(isa: OwnedTargetIsa) -> Self {
+        let builder =
+            ObjectBuilder::new(isa, "main", cranelift_module::default_libcall_names()).unwrap();
+        ObjectModule::new(builder)
+    }
+
+    // Nothing in an object file is usable before it is linked
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn finalize(mut self, _: &HashMap<String, FuncId>) -> Result<ObjectProduct, String> {
+        self.flush()?;
+        Ok(self.finish())
+    }
+}
+
+impl Backend for JITModule {
+    type Output = JitCode;
+
+    // JIT code is loaded at a fixed address
+    const PIC: bool = false;
+
+    /// The builtins are the Rust versions in `runtime` instead of `core.c`,
+    /// and extern functions are looked up in the moistc process.
+    fn for_isa(isa: OwnedTargetIsa) -> Self {
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        builder.symbols(runtime::symbols());
+        JITModule::new(builder)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.finalize_definitions()
+            .map_err(|error| format!("Can't finalize JIT code: {error}"))
+    }
+
+    fn finalize(mut self, functions: &HashMap<String, FuncId>) -> Result<JitCode, String> {
+        let main = *functions
+            .get("main")
+            .ok_or("Program has no main function")?;
+        self.flush()?;
+        Ok(JitCode {
+            main: self.get_finalized_function(main),
+            _module: self,
+        })
+    }
+}
+
+/// A program compiled into memory, ready to be called.
+pub struct JitCode {
+    main: *const u8,
+    /// Owns the memory `main` points into.
+    _module: JITModule,
+}
+
+impl JitCode {
+    /// Calls `main`, which has to take no arguments and return an integer.
+    pub fn call_main(&self) -> i64 {
+        call_function(self.main)
+    }
+}
+
+/// Calls finalized JIT code for a function that takes no arguments and
+/// returns an integer.
+pub fn call_function(code: *const u8) -> i64 {
+    // SAFETY: every function is compiled with the host's default calling
+    // convention, and the caller checked its signature.
+    let value = unsafe {
+        let function: extern "C" fn() -> i64 = std::mem::transmute(code);
+        function()
+    };
+    runtime::flush();
+    value
+}
+
+/// The ISA for `target`, with the flags its object format needs and the
+/// ones asked for in `options`.
+pub fn target_isa(
+    target: &Triple,
+    is_pic: bool,
+    options: &CompilerOptions,
+) -> Result<OwnedTargetIsa, String> {
+    // Arrays and their lengths are stored in 64-bit slots, pointers included
+    if target.pointer_width() != Ok(PointerWidth::U64) {
+        return Err(format!("{target} is not a 64-bit target"));
+    }
+
+    if options.debug_info && target.binary_format != BinaryFormat::Elf {
+        return Err(format!(
+            "Can't compile for {target}: debug info is only supported for ELF targets"
+        ));
+    }
+
+    let mut settings_builder = settings::builder();
+    // COFF has no GOT, so Windows objects use plain relocations
+    if is_pic && target.binary_format != BinaryFormat::Coff {
+        settings_builder.enable("is_pic").unwrap();
+    }
+    let opt_level = match options.opt_level {
+        OptLevel::None => "none",
+        OptLevel::Speed => "speed",
+        OptLevel::SpeedAndSize => "speed_and_size",
+    };
+    settings_builder.set("opt_level", opt_level).unwrap();
+    // Checking the IR after every pass is slow, so only moistc's own debug
+    // builds do it
+    settings_builder
+        .set("enable_verifier", &cfg!(debug_assertions).to_string())
+        .unwrap();
+    // Cranelift's tail calls need a frame pointer to find the caller's frame
+    settings_builder.enable("preserve_frame_pointers").unwrap();
+    for (key, value) in &options.cranelift_flags {
+        settings_builder
+            .set(key, value)
+            .map_err(|error| format!("Invalid Cranelift flag {key}={value}: {error}"))?;
+    }
+    let flags = settings::Flags::new(settings_builder);
+
+    let isa_builder = isa::lookup(target.clone())
+        .map_err(|error| format!("Can't compile for {target}: {error}"))?;
+    isa_builder
+        .finish(flags)
+        .map_err(|error| format!("Can't compile for {target}: {error}"))
+}
@@ -0,0 +1,50 @@
+use crate::parser::{Arg, ExternFunc};
+
+use super::types::Type;
+
+/// Runtime functions that abort the program, called as `(file, line, column)`
+/// with the location of the offending operation. `file` is the name of the
+/// file it is in, as a string.
+pub const PANIC_OVERFLOW: &str = "moist_panic_overflow";
+pub const PANIC_DIVIDE_BY_ZERO: &str = "moist_panic_divide_by_zero";
+
+/// The functions in `core.c` that .wet code can call. This is the only list of
+/// them: the type checker and the code generator are both built from it.
+pub fn builtins() -> Vec<ExternFunc> {
+    vec![
+        builtin("printint", &[("x", Type::Int)], Some(Type::Int)),
+        builtin("printintln", &[("x", Type::Int)], Some(Type::Int)),
+        builtin("printchar", &[("c", Type::Char)], Some(Type::Char)),
+        builtin("printcharln", &[("c", Type::Char)], Some(Type::Char)),
+        builtin("readchar", &[], Some(Type::Char)),
+    ]
+}
+
+/// The functions in `core.c` that only generated code calls.
+pub fn runtime_functions() -> Vec<ExternFunc> {
+    [PANIC_OVERFLOW, PANIC_DIVIDE_BY_ZERO]
+        .into_iter()
+        .map(|name| {
+            let args = [
+                ("file", Type::Array(Box::new(Type::Char))),
+                ("line", Type::Int),
+                ("column", Type::Int),
+            ];
+            builtin(name, &args, None)
+        })
+        .collect()
+}
+
+fn builtin(name: &str, args: &[(&str, Type)], ret: Option<Type>) -> ExternFunc {
+    ExternFunc {
+        name: name.to_string(),
+        args: args
+            .iter()
+            .map(|(name, arg_type)| Arg {
+                name: name.to_string(),
+                arg_type: arg_type.clone(),
+            })
+            .collect(),
+        ret,
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+
+use cranelift::codegen::{
+    gimli::{
+        self,
+        write::{
+            Address, AttributeValue, CieId, DebugFrame, DwarfUnit, EndianVec, Expression, FileId,
+            FrameTable, LineProgram, LineString, LocationList, Range, RangeList, RelocateWriter,
+            Relocation, RelocationTarget, Section, Sections, UnitEntryId, Writer,
+        },
+        Encoding, Format, LineEncoding, Register, RunTimeEndian,
+    },
+    ir::{Endianness, ValueLabel},
+    isa::{unwind::UnwindInfo, TargetIsa},
+    Context, LabelValueLoc,
+};
+use cranelift_module::FuncId;
+use cranelift_object::{
+    object::{
+        write::{self, SectionId, StandardSegment},
+        RelocationEncoding, RelocationFlags, RelocationKind, SectionKind,
+    },
+    ObjectProduct,
+};
+
+use crate::parser::Location;
+
+use super::{mir, types::Type};
+
+/// DWARF describing the functions compiled into an object file: a line table
+/// mapping machine code back to the `.wet` source, a DIE for every function
+/// and its named locals, and call frame information so debuggers can find
+/// the locals that live on the stack.
+pub struct DebugInfo {
+    dwarf: DwarfUnit,
+    endian: RunTimeEndian,
+    files: HashMap<String, FileId>,
+    /// Type DIEs, keyed by how the type is spelled.
+    types: HashMap<String, UnitEntryId>,
+    ranges: Vec<Range>,
+    frames: FrameTable,
+    cie: Option<CieId>,
+    /// The file the compile unit is named after.
+    main_file: Option<String>,
+}
+
+impl DebugInfo {
+    pub fn new(isa: &dyn TargetIsa) -> Self {
+        let encoding = Encoding {
+            format: Format::Dwarf32,
+            version: 4,
+            address_size: isa.pointer_bytes(),
+        };
+        let comp_dir = std::env::current_dir()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| ".".to_string());
+
+        let mut dwarf = DwarfUnit::new(encoding);
+        dwarf.unit.line_program = LineProgram::new(
+            encoding,
+            LineEncoding::default(),
+            LineString::new(comp_dir.as_bytes(), encoding, &mut dwarf.line_strings),
+            LineString::new("main.wet", encoding, &mut dwarf.line_strings),
+            None,
+        );
+        let producer = dwarf
+            .strings
+            .add(format!("moistc {}", env!("CARGO_PKG_VERSION")));
+        let comp_dir = dwarf.strings.add(comp_dir);
+        let root = dwarf.unit.get_mut(dwarf.unit.root());
+        root.set(gimli::DW_AT_producer, AttributeValue::StringRef(producer));
+        root.set(gimli::DW_AT_comp_dir, AttributeValue::StringRef(comp_dir));
+        // There's no language code for moistc, and C is close enough for
+        // debuggers to print integers and follow pointers
+        root.set(
+            gimli::DW_AT_language,
+            AttributeValue::Language(gimli::DW_LANG_C99),
+        );
+
+        let mut frames = FrameTable::default();
+        let cie = isa.create_systemv_cie().map(|cie| frames.add_cie(cie));
+        Self {
+            dwarf,
+            endian: match isa.endianness() {
+                Endianness::Little => RunTimeEndian::Little,
+                Endianness::Big => RunTimeEndian::Big,
+            },
+            files: HashMap::new(),
+            types: HashMap::new(),
+            ranges: Vec::new(),
+            frames,
+            cie,
+            main_file: None,
+        }
+    }
+
+    /// Describes `func`, just compiled in `context` as `fid`. The source
+    /// locations of its instructions are indices into `locations`.
+    pub fn add_function(
+        &mut self,
+        isa: &dyn TargetIsa,
+        fid: FuncId,
+        func: &mir::Function,
+        exported: bool,
+        context: &Context,
+        locations: &[Location],
+    ) {
+        let compiled = context
+            .compiled_code()
+            .expect("Function has to be compiled first");
+        let size = u64::from(compiled.buffer.total_size());
+        let symbol = fid.as_u32() as usize;
+        let address = |offset: u32| Address::Symbol {
+            symbol,
+            addend: i64::from(offset),
+        };
+        if func.name == "main" || self.main_file.is_none() {
+            self.main_file = Some(func.file.clone());
+        }
+
+        let file = self.file(&func.file);
+        let line_program = &mut self.dwarf.unit.line_program;
+        line_program.begin_sequence(Some(address(0)));
+        let mut previous = None;
+        let mut row = |offset: u32, location: &Location| {
+            if previous == Some(*location) {
+                return;
+            }
+            previous = Some(*location);
+            let row = line_program.row();
+            row.address_offset = u64::from(offset);
+            row.file = file;
+            row.line = location.line as u64;
+            row.column = location.column as u64;
+            line_program.generate_row();
+        };
+        row(0, &func.location);
+        for srcloc in compiled.buffer.get_srclocs_sorted() {
+            if !srcloc.loc.is_default() {
+                row(srcloc.start, &locations[srcloc.loc.bits() as usize]);
+            }
+        }
+        line_program.end_sequence(size);
+        self.ranges.push(Range::StartLength {
+            begin: address(0),
+            length: size,
+        });
+
+        if let (Some(cie), Ok(Some(UnwindInfo::SystemV(unwind)))) =
+            (self.cie, compiled.create_unwind_info(isa))
+        {
+            self.frames.add_fde(cie, unwind.to_fde(address(0)));
+        }
+
+        let ret = self.type_entry(&func.ret);
+        let root = self.dwarf.unit.root();
+        let subprogram = self.dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+        let name = self.dwarf.strings.add(func.name.as_str());
+        let mut frame_base = Expression::new();
+        frame_base.op(gimli::DW_OP_call_frame_cfa);
+        let entry = self.dwarf.unit.get_mut(subprogram);
+        entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        entry.set(
+            gimli::DW_AT_decl_file,
+            AttributeValue::FileIndex(Some(file)),
+        );
+        entry.set(
+            gimli::DW_AT_decl_line,
+            AttributeValue::Udata(func.location.line as u64),
+        );
+        entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(ret));
+        entry.set(gimli::DW_AT_external, AttributeValue::Flag(exported));
+        entry.set(gimli::DW_AT_low_pc, AttributeValue::Address(address(0)));
+        entry.set(gimli::DW_AT_high_pc, AttributeValue::Udata(size));
+        entry.set(gimli::DW_AT_frame_base, AttributeValue::Exprloc(frame_base));
+
+        for (i, local) in func.locals.iter().enumerate() {
+            let Some(name) = &local.name else {
+                continue;
+            };
+            let tag = if i < func.arg_count {
+                gimli::DW_TAG_formal_parameter
+            } else {
+                gimli::DW_TAG_variable
+            };
+            let local_type = self.type_entry(&local.local_type);
+            let variable = self.dwarf.unit.add(subprogram, tag);
+            let name = self.dwarf.strings.add(name.as_str());
+            // Where the local lives while it's alive, nothing if it never is
+            let ranges = compiled
+                .value_labels_ranges
+                .get(&ValueLabel::from_u32(i as u32))
+                .map(|ranges| {
+                    ranges
+                        .iter()
+                        .filter_map(|range| {
+                            Some(gimli::write::Location::StartLength {
+                                begin: address(range.start),
+                                length: u64::from(range.end - range.start),
+                                data: value_location(isa, range.loc)?,
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .filter(|ranges| !ranges.is_empty())
+                .map(|ranges| self.dwarf.unit.locations.add(LocationList(ranges)));
+            let entry = self.dwarf.unit.get_mut(variable);
+            entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+            entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(local_type));
+            if let Some(ranges) = ranges {
+                entry.set(
+                    gimli::DW_AT_location,
+                    AttributeValue::LocationListRef(ranges),
+                );
+            }
+        }
+    }
+
+    /// Adds the debug sections to `product`, with relocations against the
+    /// functions they describe.
+    pub fn write(mut self, product: &mut ObjectProduct) -> Result<(), String> {
+        let main_file = self.main_file.take().unwrap_or_default();
+        let name = self.dwarf.strings.add(file_name(&main_file));
+        let ranges = self.dwarf.unit.ranges.add(RangeList(self.ranges));
+        let root = self.dwarf.unit.get_mut(self.dwarf.unit.root());
+        root.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+        root.set(
+            gimli::DW_AT_low_pc,
+            AttributeValue::Address(Address::Constant(0)),
+        );
+        root.set(gimli::DW_AT_ranges, AttributeValue::RangeListRef(ranges));
+
+        let mut sections = Sections::new(SectionWriter::new(self.endian));
+        self.dwarf.write(&mut sections).map_err(dwarf_error)?;
+        let mut debug_frame = DebugFrame::from(SectionWriter::new(self.endian));
+        self.frames
+            .write_debug_frame(&mut debug_frame)
+            .map_err(dwarf_error)?;
+
+        let mut written = Vec::new();
+        sections
+            .for_each(|id, section| {
+                written.push((id, section));
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        written.push((debug_frame.id(), &debug_frame));
+
+        let segment = product.object.segment_name(StandardSegment::Debug).to_vec();
+        let mut section_ids = HashMap::<gimli::SectionId, SectionId>::new();
+        for (id, section) in &written {
+            if section.data.len() == 0 {
+                continue;
+            }
+            let section_id = product.object.add_section(
+                segment.clone(),
+                id.name().as_bytes().to_vec(),
+                SectionKind::Debug,
+            );
+            product
+                .object
+                .append_section_data(section_id, section.data.slice(), 1);
+            section_ids.insert(*id, section_id);
+        }
+        for (id, section) in &written {
+            for relocation in &section.relocations {
+                let symbol = match relocation.target {
+                    RelocationTarget::Symbol(symbol) => {
+                        product.function_symbol(FuncId::from_u32(symbol as u32))
+                    }
+                    RelocationTarget::Section(target) => {
+                        product.object.section_symbol(section_ids[&target])
+                    }
+                };
+                product
+                    .object
+                    .add_relocation(
+                        section_ids[id],
+                        write::Relocation {
+                            offset: relocation.offset as u64,
+                            symbol,
+                            addend: relocation.addend,
+                            flags: RelocationFlags::Generic {
+                                kind: RelocationKind::Absolute,
+                                encoding: RelocationEncoding::Generic,
+                                size: relocation.size * 8,
+                            },
+                        },
+                    )
+                    .map_err(|error| format!("Failed to write debug info: {error}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: &str) -> FileId {
+        if let Some(file) = self.files.get(path) {
+            return *file;
+        }
+        let line_program = &mut self.dwarf.unit.line_program;
+        let directory = line_program.default_directory();
+        let name = LineString::new(
+            file_name(path),
+            line_program.encoding(),
+            &mut self.dwarf.line_strings,
+        );
+        let file = line_program.add_file(name, directory, None);
+        self.files.insert(path.to_string(), file);
+        file
+    }
+
+    /// The DIE for `value_type`, added the first time it is needed.
+    fn type_entry(&mut self, value_type: &Type) -> UnitEntryId {
+        let name = value_type.to_string();
+        if let Some(entry) = self.types.get(&name) {
+            return *entry;
+        }
+        let root = self.dwarf.unit.root();
+        let entry = match value_type {
+            Type::Array(element_type) => {
+                // A pointer to the length, followed by the elements in slots
+                // as wide as the length
+                let element_type = self.type_entry(element_type);
+                let int = self.type_entry(&Type::Int);
+                let array = self.dwarf.unit.add(root, gimli::DW_TAG_structure_type);
+                let name = self.dwarf.strings.add(name.as_str());
+                let entry = self.dwarf.unit.get_mut(array);
+                entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+                entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(8));
+
+                let len = self.dwarf.unit.add(array, gimli::DW_TAG_member);
+                let len_name = self.dwarf.strings.add("len");
+                let entry = self.dwarf.unit.get_mut(len);
+                entry.set(gimli::DW_AT_name, AttributeValue::StringRef(len_name));
+                entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(int));
+                entry.set(gimli::DW_AT_data_member_location, AttributeValue::Udata(0));
+
+                let elements_type = self.dwarf.unit.add(root, gimli::DW_TAG_array_type);
+                let entry = self.dwarf.unit.get_mut(elements_type);
+                entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(element_type));
+                entry.set(gimli::DW_AT_byte_stride, AttributeValue::Udata(8));
+                let subrange = self
+                    .dwarf
+                    .unit
+                    .add(elements_type, gimli::DW_TAG_subrange_type);
+                self.dwarf
+                    .unit
+                    .get_mut(subrange)
+                    .set(gimli::DW_AT_type, AttributeValue::UnitRef(int));
+
+                let elements = self.dwarf.unit.add(array, gimli::DW_TAG_member);
+                let elements_name = self.dwarf.strings.add("elements");
+                let entry = self.dwarf.unit.get_mut(elements);
+                entry.set(gimli::DW_AT_name, AttributeValue::StringRef(elements_name));
+                entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(elements_type));
+                entry.set(gimli::DW_AT_data_member_location, AttributeValue::Udata(8));
+
+                let pointer = self.dwarf.unit.add(root, gimli::DW_TAG_pointer_type);
+                let entry = self.dwarf.unit.get_mut(pointer);
+                entry.set(gimli::DW_AT_type, AttributeValue::UnitRef(array));
+                entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(8));
+                pointer
+            }
+            value_type => {
+                let (size, encoding) = match value_type {
+                    Type::Int8 => (1, gimli::DW_ATE_signed),
+                    Type::Int16 => (2, gimli::DW_ATE_signed),
+                    Type::Int32 => (4, gimli::DW_ATE_signed),
+                    Type::UInt8 => (1, gimli::DW_ATE_unsigned),
+                    Type::UInt16 => (2, gimli::DW_ATE_unsigned),
+                    Type::UInt32 => (4, gimli::DW_ATE_unsigned),
+                    Type::UInt64 => (8, gimli::DW_ATE_unsigned),
+                    Type::Float => (8, gimli::DW_ATE_float),
+                    Type::Bool => (8, gimli::DW_ATE_boolean),
+                    // Held in 64 bits, of which a UTF-32 code unit is the low half
+                    Type::Char => (4, gimli::DW_ATE_UTF),
+                    _ => (8, gimli::DW_ATE_signed),
+                };
+                let base = self.dwarf.unit.add(root, gimli::DW_TAG_base_type);
+                let name = self.dwarf.strings.add(name.as_str());
+                let entry = self.dwarf.unit.get_mut(base);
+                entry.set(gimli::DW_AT_name, AttributeValue::StringRef(name));
+                entry.set(gimli::DW_AT_byte_size, AttributeValue::Udata(size));
+                entry.set(gimli::DW_AT_encoding, AttributeValue::Encoding(encoding));
+                base
+            }
+        };
+        self.types.insert(name, entry);
+        entry
+    }
+}
+
+/// A DWARF expression for where a value is, relative to the frame base for
+/// values on the stack.
+fn value_location(isa: &dyn TargetIsa, location: LabelValueLoc) -> Option<Expression> {
+    let mut expression = Expression::new();
+    match location {
+        LabelValueLoc::Reg(reg) => {
+            let register = isa.map_regalloc_reg_to_dwarf(reg).ok()?;
+            expression.op_reg(Register(register));
+        }
+        LabelValueLoc::CFAOffset(offset) => expression.op_fbreg(offset),
+    }
+    Some(expression)
+}
+
+/// How the file at `path` is named in the debug info, which can't be empty.
+fn file_name(path: &str) -> &str {
+    if path.is_empty() {
+        "<unknown>"
+    } else {
+        path
+    }
+}
+
+fn dwarf_error(error: gimli::write::Error) -> String {
+    format!("Failed to write debug info: {error}")
+}
+
+/// The contents of a debug section, and the relocations to apply to it once
+/// the symbols it refers to have addresses.
+#[derive(Clone)]
+struct SectionWriter {
+    data: EndianVec<RunTimeEndian>,
+    relocations: Vec<Relocation>,
+}
+
+impl SectionWriter {
+    fn new(endian: RunTimeEndian) -> Self {
+        Self {
+            data: EndianVec::new(endian),
+            relocations: Vec::new(),
+        }
+    }
+}
+
+impl RelocateWriter for SectionWriter {
+    type Writer = EndianVec<RunTimeEndian>;
+
+    fn writer(&self) -> &Self::Writer {
+        &self.data
+    }
+
+    fn writer_mut(&mut self) -> &mut Self::Writer {
+        &mut self.data
+    }
+
+    fn relocate(&mut self, relocation: Relocation) {
+        self.relocations.push(relocation);
+    }
+}
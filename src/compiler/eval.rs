@@ -0,0 +1,106 @@
+//! Integer arithmetic done exactly the way compiled code does it. The
+//! interpreter runs programs with it, and constant folding in the typed tree
+//! and in MIR uses it to evaluate operations ahead of time.
+
+use crate::parser::Op;
+
+use super::types::Type;
+
+/// Applies `op` to two values of `operand_type` exactly like the compiled
+/// code does, or returns the message of the panic it would hit. Overflow only
+/// panics for `+`, `-` and `*` when `checked_arithmetic` is set.
+pub fn operation(
+    operand_type: &Type,
+    lhs: i64,
+    op: &Op,
+    rhs: i64,
+    checked_arithmetic: bool,
+) -> Result<i64, &'static str> {
+    let unsigned = operand_type.is_unsigned();
+    // Whether a result computed in 64 bits doesn't fit in the type
+    let overflows = |result: i64| truncate(operand_type, result) != result;
+    let result = match op {
+        Op::Add | Op::Sub | Op::Mul | Op::WrappingAdd | Op::WrappingSub | Op::WrappingMul => {
+            let (result, overflowed) = if unsigned {
+                let (lhs, rhs) = (lhs as u64, rhs as u64);
+                let (result, overflowed) = match op {
+                    Op::Add | Op::WrappingAdd => lhs.overflowing_add(rhs),
+                    Op::Sub | Op::WrappingSub => lhs.overflowing_sub(rhs),
+                    _ => lhs.overflowing_mul(rhs),
+                };
+                (result as i64, overflowed)
+            } else {
+                match op {
+                    Op::Add | Op::WrappingAdd => lhs.overflowing_add(rhs),
+                    Op::Sub | Op::WrappingSub => lhs.overflowing_sub(rhs),
+                    _ => lhs.overflowing_mul(rhs),
+                }
+            };
+            let checked = matches!(op, Op::Add | Op::Sub | Op::Mul) && checked_arithmetic;
+            if checked && (overflowed || overflows(result)) {
+                return Err("arithmetic overflow");
+            }
+            result
+        }
+        Op::Div | Op::Rem => {
+            if rhs == 0 {
+                return Err("division by zero");
+            }
+            match (op, unsigned) {
+                (Op::Div, true) => (lhs as u64 / rhs as u64) as i64,
+                (_, true) => (lhs as u64 % rhs as u64) as i64,
+                (Op::Div, false) => match lhs.checked_div(rhs) {
+                    Some(result) if !overflows(result) => result,
+                    _ => return Err("arithmetic overflow"),
+                },
+                _ => lhs.wrapping_rem(rhs),
+            }
+        }
+        Op::BitAnd => lhs & rhs,
+        Op::BitOr => lhs | rhs,
+        Op::BitXor => lhs ^ rhs,
+        // Shift amounts wrap around at the width of the type
+        Op::Shl => lhs << (rhs as u32 % bits(operand_type)),
+        Op::Shr if unsigned => ((lhs as u64) >> (rhs as u32 % bits(operand_type))) as i64,
+        Op::Shr => lhs >> (rhs as u32 % bits(operand_type)),
+        comparison => {
+            let ordering = if unsigned {
+                (lhs as u64).cmp(&(rhs as u64))
+            } else {
+                lhs.cmp(&rhs)
+            };
+            return Ok(match comparison {
+                Op::Ge => ordering.is_ge(),
+                Op::Le => ordering.is_le(),
+                Op::Gt => ordering.is_gt(),
+                Op::Lt => ordering.is_lt(),
+                Op::Eq => ordering.is_eq(),
+                _ => ordering.is_ne(),
+            } as i64);
+        }
+    };
+    Ok(truncate(operand_type, result))
+}
+
+/// How many bits the compiled code uses for a value of type `t`.
+fn bits(t: &Type) -> u32 {
+    match t {
+        Type::Int8 | Type::UInt8 => 8,
+        Type::Int16 | Type::UInt16 => 16,
+        Type::Int32 | Type::UInt32 => 32,
+        _ => 64,
+    }
+}
+
+/// Wraps `x` around to the width of `t` and widens it back to 64 bits.
+pub fn truncate(t: &Type, x: i64) -> i64 {
+    match (bits(t), t.is_unsigned()) {
+        (8, false) => x as i8 as i64,
+        (8, true) => x as u8 as i64,
+        (16, false) => x as i16 as i64,
+        (16, true) => x as u16 as i64,
+        (32, false) => x as i32 as i64,
+        (32, true) => x as u32 as i64,
+        _ => x,
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt::{self, Display};
+
+use crate::parser::Location;
+
+use super::{
+    eval::{operation, truncate},
+    types::{get_type, Type, TypedExpr, TypedFunc, TypedProgram, TypedValue},
+};
+
+/// An operation on constants that is certain to panic if it runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The file the operation is in, empty for code typed into the REPL.
+    pub file: String,
+    pub location: Location,
+    pub message: &'static str,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+        }
+        write!(
+            f,
+            "{}:{}: {}",
+            self.location.line, self.location.column, self.message
+        )
+    }
+}
+
+/// Evaluates everything in `program` that only depends on literals:
+/// arithmetic, comparisons and casts, the length of literal arrays and `if`s
+/// with a literal condition. Operations on literals that would panic are
+/// reported instead, unless they sit in a branch that is never taken.
+pub fn fold_program(
+    program: TypedProgram,
+    checked_arithmetic: bool,
+) -> Result<TypedProgram, Vec<Diagnostic>> {
+    let mut folder = Folder {
+        checked_arithmetic,
+        file: String::new(),
+        diagnostics: Vec::new(),
+    };
+    let funcs = program
+        .funcs
+        .into_iter()
+        .map(|func| {
+            folder.file.clone_from(&func.file);
+            TypedFunc {
+                body: folder.expr(func.body),
+                ..func
+            }
+        })
+        .collect();
+    if !folder.diagnostics.is_empty() {
+        return Err(folder.diagnostics);
+    }
+    Ok(TypedProgram {
+        funcs,
+        externs: program.externs,
+    })
+}
+
+struct Folder {
+    checked_arithmetic: bool,
+    /// The file of the function being folded.
+    file: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Folder {
+    fn expr(&mut self, expr: TypedExpr) -> TypedExpr {
+        match expr {
+            TypedExpr::Operation(result_type, lhs, op, rhs, location) => {
+                let lhs = self.expr(*lhs);
+                let rhs = self.expr(*rhs);
+                if let (Some(x), Some(y)) = (constant(&lhs), constant(&rhs)) {
+                    let operand_type = get_type(&lhs);
+                    match operation(&operand_type, x, &op, y, self.checked_arithmetic) {
+                        Ok(value) => {
+                            if let Some(value) = literal(&result_type, value) {
+                                return TypedExpr::Value(result_type, value);
+                            }
+                        }
+                        Err(message) => self.diagnostics.push(Diagnostic {
+                            file: self.file.clone(),
+                            location,
+                            message,
+                        }),
+                    }
+                }
+                TypedExpr::Operation(result_type, Box::new(lhs), op, Box::new(rhs), location)
+            }
+            TypedExpr::Cast { value, to } => {
+                let value = self.expr(*value);
+                if let Some(value) = constant(&value).and_then(|x| literal(&to, truncate(&to, x))) {
+                    return TypedExpr::Value(to, value);
+                }
+                TypedExpr::Cast {
+                    value: Box::new(value),
+                    to,
+                }
+            }
+            TypedExpr::Len(array) => {
+                let array = self.expr(*array);
+                match &array {
+                    // Only when leaving the elements out can't skip a call
+                    TypedExpr::Value(_, TypedValue::Array(elements))
+                        if elements.iter().all(|element| constant(element).is_some()) =>
+                    {
+                        TypedExpr::Value(Type::Int, TypedValue::Number(elements.len() as i64))
+                    }
+                    _ => TypedExpr::Len(Box::new(array)),
+                }
+            }
+            TypedExpr::IfThen {
+                condition,
+                then,
+                other,
+            } => {
+                let condition = self.expr(*condition);
+                match constant(&condition) {
+                    // The other branch is dropped without being looked at
+                    Some(0) => self.expr(*other),
+                    Some(_) => self.expr(*then),
+                    None => TypedExpr::IfThen {
+                        condition: Box::new(condition),
+                        then: Box::new(self.expr(*then)),
+                        other: Box::new(self.expr(*other)),
+                    },
+                }
+            }
+            TypedExpr::Value(value_type, TypedValue::Array(elements)) => TypedExpr::Value(
+                value_type,
+                TypedValue::Array(elements.into_iter().map(|e| self.expr(e)).collect()),
+            ),
+            TypedExpr::Def { ident, value } => TypedExpr::Def {
+                ident,
+                value: Box::new(self.expr(*value)),
+            },
+            TypedExpr::Then { lhs, rhs } => TypedExpr::Then {
+                lhs: Box::new(self.expr(*lhs)),
+                rhs: Box::new(self.expr(*rhs)),
+            },
+            TypedExpr::FunctionCall(ret, name, args, location) => TypedExpr::FunctionCall(
+                ret,
+                name,
+                args.into_iter().map(|arg| self.expr(arg)).collect(),
+                location,
+            ),
+            TypedExpr::Index {
+                target,
+                index,
+                contained_type,
+            } => TypedExpr::Index {
+                target: Box::new(self.expr(*target)),
+                index: Box::new(self.expr(*index)),
+                contained_type,
+            },
+            TypedExpr::Each {
+                body,
+                ident,
+                target,
+            } => TypedExpr::Each {
+                body: Box::new(self.expr(*body)),
+                ident,
+                target: Box::new(self.expr(*target)),
+            },
+            expr @ (TypedExpr::Value(..) | TypedExpr::Ident(..)) => expr,
+        }
+    }
+}
+
+/// The value of a literal integer, bool or char, held like at runtime.
+fn constant(expr: &TypedExpr) -> Option<i64> {
+    match expr {
+        TypedExpr::Value(_, TypedValue::Number(x)) => Some(*x),
+        TypedExpr::Value(_, TypedValue::Bool(x)) => Some(i64::from(*x)),
+        TypedExpr::Value(_, TypedValue::Char(x)) => Some(i64::from(u32::from(*x))),
+        _ => None,
+    }
+}
+
+/// The literal of type `value_type` that holds `value`, if there is one.
+fn literal(value_type: &Type, value: i64) -> Option<TypedValue> {
+    match value_type {
+        Type::Bool => match value {
+            0 | 1 => Some(TypedValue::Bool(value == 1)),
+            _ => None,
+        },
+        Type::Char => u32::try_from(value)
+            .ok()
+            .and_then(char::from_u32)
+            .map(TypedValue::Char),
+        value_type if value_type.is_integer() => Some(TypedValue::Number(value)),
+        _ => None,
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    mem,
+};
+
+use crate::{
+    fmt::symbol,
+    parser::{Location, Op},
+};
+
+use tracing::info_span;
+
+use super::types::{get_type, Type, TypedExpr, TypedFunc, TypedProgram, TypedValue};
+
+pub use self::passes::optimize;
+
+mod passes;
+
+/// The mid-level IR: every function in A-normal form, where each operation
+/// works on constants and locals and stores its result in another local.
+/// Control flow stays structured as `if` and `each` statements.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub funcs: Vec<Function>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: String,
+    /// The first `arg_count` locals are the arguments.
+    pub arg_count: usize,
+    pub ret: Type,
+    pub locals: Vec<LocalDecl>,
+    pub body: Block,
+    pub location: Location,
+    pub file: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalDecl {
+    /// The variable this local holds, `None` for temporaries. Temporaries are
+    /// assigned exactly once, before any use, while variables can be
+    /// assigned over and over again.
+    pub name: Option<String>,
+    pub local_type: Type,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Local(pub usize);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A constant held like a runtime value: sign extended to 64 bits for
+    /// signed types and zero extended for everything else.
+    Const(i64, Type),
+    Local(Local),
+}
+
+/// A list of statements followed by the value they produce.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub result: Operand,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Assign(Local, Rvalue),
+    If {
+        dest: Local,
+        condition: Operand,
+        then: Block,
+        other: Block,
+    },
+    /// Runs `body` with `var` set to 1 up to `max`.
+    Each {
+        var: Local,
+        max: Operand,
+        body: Vec<Stmt>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Rvalue {
+    Use(Operand),
+    /// `Add`, `Sub` and `Mul` always check for overflow here, unchecked
+    /// arithmetic is lowered to the wrapping operators.
+    Binary(Op, Operand, Operand, Location),
+    Cast(Operand, Type),
+    Call(String, Vec<Operand>, Location),
+    Array(Vec<Operand>),
+    Len(Operand),
+    Index(Operand, Operand),
+}
+
+impl Rvalue {
+    /// Whether evaluating it can't be observed, apart from its result.
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Rvalue::Binary(op, ..) => {
+                !matches!(op, Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Rem)
+            }
+            Rvalue::Call(..) => false,
+            _ => true,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Rvalue::Use(operand) | Rvalue::Cast(operand, _) | Rvalue::Len(operand) => {
+                vec![operand]
+            }
+            Rvalue::Binary(_, lhs, rhs, _) | Rvalue::Index(lhs, rhs) => vec![lhs, rhs],
+            Rvalue::Call(_, operands, _) | Rvalue::Array(operands) => operands.iter().collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Rvalue::Use(operand) | Rvalue::Cast(operand, _) | Rvalue::Len(operand) => {
+                vec![operand]
+            }
+            Rvalue::Binary(_, lhs, rhs, _) | Rvalue::Index(lhs, rhs) => vec![lhs, rhs],
+            Rvalue::Call(_, operands, _) | Rvalue::Array(operands) => operands.iter_mut().collect(),
+        }
+    }
+}
+
+impl Function {
+    pub fn local_type(&self, local: Local) -> &Type {
+        &self.locals[local.0].local_type
+    }
+
+    pub fn operand_type<'a>(&'a self, operand: &'a Operand) -> &'a Type {
+        match operand {
+            Operand::Const(_, const_type) => const_type,
+            Operand::Local(local) => self.local_type(*local),
+        }
+    }
+}
+
+/// Lowers every function of a type checked program. Extern functions have
+/// nothing to lower and are left to the code generator.
+pub fn lower_program(program: &TypedProgram, checked_arithmetic: bool) -> Program {
+    let _span = info_span!("lower").entered();
+    Program {
+        funcs: program
+            .funcs
+            .iter()
+            .map(|func| lower_function(func, checked_arithmetic))
+            .collect(),
+    }
+}
+
+fn lower_function(func: &TypedFunc, checked_arithmetic: bool) -> Function {
+    let _span = info_span!("function", name = %func.name).entered();
+    let mut lowering = Lowering {
+        locals: Vec::new(),
+        variables: HashMap::new(),
+        stmts: Vec::new(),
+        checked_arithmetic,
+    };
+    for arg in &func.args {
+        lowering.variable(&arg.name, &arg.arg_type);
+    }
+    let body = lowering.block(&func.body);
+    Function {
+        name: func.name.clone(),
+        arg_count: func.args.len(),
+        ret: func.func_type.ret.clone(),
+        locals: lowering.locals,
+        body,
+        location: func.location,
+        file: func.file.clone(),
+    }
+}
+
+struct Lowering {
+    locals: Vec<LocalDecl>,
+    /// The local each variable name currently refers to.
+    variables: HashMap<String, Local>,
+    /// Statements of the block being lowered.
+    stmts: Vec<Stmt>,
+    checked_arithmetic: bool,
+}
+
+impl Lowering {
+    fn local(&mut self, name: Option<String>, local_type: Type) -> Local {
+        self.locals.push(LocalDecl { name, local_type });
+        Local(self.locals.len() - 1)
+    }
+
+    /// The local for variable `name`. Defining a name again assigns to the
+    /// same local, so loops can update it, unless the type changes.
+    fn variable(&mut self, name: &str, variable_type: &Type) -> Local {
+        match self.variables.get(name) {
+            Some(local) if self.locals[local.0].local_type == *variable_type => *local,
+            _ => {
+                let local = self.local(Some(name.to_string()), variable_type.clone());
+                self.variables.insert(name.to_string(), local);
+                local
+            }
+        }
+    }
+
+    /// Stores `rvalue` in a new temporary.
+    fn temporary(&mut self, rvalue: Rvalue, temporary_type: Type) -> Operand {
+        let local = self.local(None, temporary_type);
+        self.stmts.push(Stmt::Assign(local, rvalue));
+        Operand::Local(local)
+    }
+
+    fn block(&mut self, expr: &TypedExpr) -> Block {
+        let outer = mem::take(&mut self.stmts);
+        let result = self.expr(expr);
+        Block {
+            stmts: mem::replace(&mut self.stmts, outer),
+            result,
+        }
+    }
+
+    fn expr(&mut self, expr: &TypedExpr) -> Operand {
+        match expr {
+            TypedExpr::Value(value_type, TypedValue::Number(x)) => {
+                Operand::Const(*x, value_type.clone())
+            }
+            TypedExpr::Value(value_type, TypedValue::Bool(x)) => {
+                Operand::Const(i64::from(*x), value_type.clone())
+            }
+            TypedExpr::Value(value_type, TypedValue::Char(x)) => {
+                Operand::Const(i64::from(u32::from(*x)), value_type.clone())
+            }
+            TypedExpr::Value(value_type, TypedValue::Array(elements)) => {
+                let elements = elements.iter().map(|element| self.expr(element)).collect();
+                self.temporary(Rvalue::Array(elements), value_type.clone())
+            }
+            TypedExpr::Ident(ident_type, ident) => {
+                let local = self
+                    .variables
+                    .get(ident)
+                    .copied()
+                    .unwrap_or_else(|| panic!("Found undefined variable {}", ident));
+                // Copied, so assigning the variable later can't change the
+                // value this use already saw
+                self.temporary(Rvalue::Use(Operand::Local(local)), ident_type.clone())
+            }
+            TypedExpr::Operation(result_type, lhs, op, rhs, location) => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                let op = match op {
+                    Op::Add if !self.checked_arithmetic => Op::WrappingAdd,
+                    Op::Sub if !self.checked_arithmetic => Op::WrappingSub,
+                    Op::Mul if !self.checked_arithmetic => Op::WrappingMul,
+                    op => op.clone(),
+                };
+                self.temporary(Rvalue::Binary(op, lhs, rhs, *location), result_type.clone())
+            }
+            TypedExpr::Cast { value, to } => {
+                let value = self.expr(value);
+                self.temporary(Rvalue::Cast(value, to.clone()), to.clone())
+            }
+            TypedExpr::Def { ident, value } => {
+                let value_type = get_type(value);
+                let value = self.expr(value);
+                let local = self.variable(ident, &value_type);
+                self.stmts
+                    .push(Stmt::Assign(local, Rvalue::Use(value.clone())));
+                value
+            }
+            TypedExpr::Then { lhs, rhs } => {
+                self.expr(lhs);
+                self.expr(rhs)
+            }
+            TypedExpr::FunctionCall(ret, name, args, location) => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                self.temporary(Rvalue::Call(name.clone(), args, *location), ret.clone())
+            }
+            TypedExpr::IfThen {
+                condition,
+                then,
+                other,
+            } => {
+                let condition = self.expr(condition);
+                let then = self.block(then);
+                let other = self.block(other);
+                let dest = self.local(None, get_type(expr));
+                self.stmts.push(Stmt::If {
+                    dest,
+                    condition,
+                    then,
+                    other,
+                });
+                Operand::Local(dest)
+            }
+            TypedExpr::Index {
+                target,
+                index,
+                contained_type,
+            } => {
+                let target = self.expr(target);
+                let index = self.expr(index);
+                self.temporary(Rvalue::Index(target, index), contained_type.clone())
+            }
+            TypedExpr::Each {
+                body,
+                ident,
+                target,
+            } => {
+                let max = self.expr(target);
+                let var = self.local(Some(ident.clone()), Type::Int);
+                self.variables.insert(ident.clone(), var);
+                let body = self.block(body).stmts;
+                self.stmts.push(Stmt::Each { var, max, body });
+                Operand::Const(0, Type::Int)
+            }
+            TypedExpr::Len(array) => {
+                let array = self.expr(array);
+                self.temporary(Rvalue::Len(array), Type::Int)
+            }
+        }
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for func in &self.funcs {
+            write!(f, "{func}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a function with one statement per line. Variables are shown as
+/// `name.N` and temporaries as `%N`, where `N` is the local's number.
+impl Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args = (0..self.arg_count)
+            .map(|i| format!("{}: {}", self.name_of(Local(i)), self.local_type(Local(i))))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(f, "funion {}[{args}] -> {} (", self.name, self.ret)?;
+        self.write_stmts(f, &self.body.stmts, 1)?;
+        writeln!(f, "\treturn {}", self.operand(&self.body.result))?;
+        writeln!(f, ")")
+    }
+}
+
+impl Function {
+    fn name_of(&self, local: Local) -> String {
+        match &self.locals[local.0].name {
+            Some(name) => format!("{name}.{}", local.0),
+            None => format!("%{}", local.0),
+        }
+    }
+
+    fn operand(&self, operand: &Operand) -> String {
+        match operand {
+            Operand::Const(x, Type::Int) => x.to_string(),
+            Operand::Const(x, const_type) => format!("{x} as {const_type}"),
+            Operand::Local(local) => self.name_of(*local),
+        }
+    }
+
+    fn rvalue(&self, rvalue: &Rvalue) -> String {
+        let operands = |operands: &[Operand]| {
+            operands
+                .iter()
+                .map(|operand| self.operand(operand))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match rvalue {
+            Rvalue::Use(operand) => self.operand(operand),
+            Rvalue::Binary(op, lhs, rhs, _) => {
+                format!("{} {} {}", self.operand(lhs), symbol(op), self.operand(rhs))
+            }
+            Rvalue::Cast(operand, to) => format!("{} as {to}", self.operand(operand)),
+            Rvalue::Call(name, args, _) => format!("{name}[{}]", operands(args)),
+            Rvalue::Array(elements) => format!("{{{}}}", operands(elements)),
+            Rvalue::Len(operand) => format!("#{}#", self.operand(operand)),
+            Rvalue::Index(target, index) => {
+                format!("{}{{{}}}", self.operand(target), self.operand(index))
+            }
+        }
+    }
+
+    fn write_stmts(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        stmts: &[Stmt],
+        indent: usize,
+    ) -> fmt::Result {
+        let tabs = "\t".repeat(indent);
+        for stmt in stmts {
+            match stmt {
+                Stmt::Assign(local, rvalue) => writeln!(
+                    f,
+                    "{tabs}{}: {} = {}",
+                    self.name_of(*local),
+                    self.local_type(*local),
+                    self.rvalue(rvalue)
+                )?,
+                Stmt::If {
+                    dest,
+                    condition,
+                    then,
+                    other,
+                } => {
+                    writeln!(
+                        f,
+                        "{tabs}{}: {} = if {} then",
+                        self.name_of(*dest),
+                        self.local_type(*dest),
+                        self.operand(condition)
+                    )?;
+                    self.write_stmts(f, &then.stmts, indent + 1)?;
+                    writeln!(f, "{tabs}\tyield {}", self.operand(&then.result))?;
+                    writeln!(f, "{tabs}else")?;
+                    self.write_stmts(f, &other.stmts, indent + 1)?;
+                    writeln!(f, "{tabs}\tyield {}", self.operand(&other.result))?;
+                    writeln!(f, "{tabs}end")?;
+                }
+                Stmt::Each { var, max, body } => {
+                    writeln!(
+                        f,
+                        "{tabs}each {} in {} do",
+                        self.name_of(*var),
+                        self.operand(max)
+                    )?;
+                    self.write_stmts(f, body, indent + 1)?;
+                    writeln!(f, "{tabs}end")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use crate::compiler::{
+    eval::{operation, truncate},
+    OptLevel,
+};
+
+use tracing::info_span;
+
+use super::{Block, Function, Local, Operand, Program, Rvalue, Stmt};
+
+/// Functions with at most this many statements are inlined into callers.
+const INLINE_THRESHOLD: usize = 12;
+
+/// A transformation of a whole program.
+pub trait Pass {
+    /// What the pass is called in logs and timings.
+    fn name(&self) -> &'static str;
+
+    fn run(&self, program: &mut Program);
+}
+
+/// Runs passes in the order they were added.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn add(&mut self, pass: impl Pass + 'static) {
+        self.passes.push(Box::new(pass));
+    }
+
+    pub fn run(&self, program: &mut Program) {
+        for pass in &self.passes {
+            let _span = info_span!("pass", name = pass.name()).entered();
+            pass.run(program);
+        }
+    }
+}
+
+/// Runs the passes for `opt_level`. Nothing is changed without
+/// optimizations, so the code generated matches the source one to one.
+pub fn optimize(program: &mut Program, opt_level: OptLevel) {
+    if opt_level == OptLevel::None {
+        return;
+    }
+    let _span = info_span!("optimize").entered();
+    let mut passes = PassManager::default();
+    passes.add(Inline);
+    passes.add(ConstantFold);
+    passes.add(HoistLen);
+    passes.add(DeadDefs);
+    passes.run(program);
+}
+
+/// Replaces calls to small functions that don't call themselves with the
+/// function's body. Only functions from the same file are inlined, so the
+/// source locations in a function all point into its own file.
+pub struct Inline;
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn run(&self, program: &mut Program) {
+        // Bodies from before this pass, so inlining can't go on forever
+        let small = program
+            .funcs
+            .iter()
+            .filter(|func| stmt_count(&func.body.stmts) <= INLINE_THRESHOLD)
+            .filter(|func| !calls(&func.body.stmts, &func.name))
+            .map(|func| (func.name.clone(), func.clone()))
+            .collect::<HashMap<_, _>>();
+        for func in &mut program.funcs {
+            let stmts = mem::take(&mut func.body.stmts);
+            func.body.stmts = inline_stmts(func, stmts, &small);
+        }
+    }
+}
+
+fn inline_stmts(
+    func: &mut Function,
+    stmts: Vec<Stmt>,
+    small: &HashMap<String, Function>,
+) -> Vec<Stmt> {
+    let mut inlined = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(dest, Rvalue::Call(name, args, _))
+                if name != func.name
+                    && small
+                        .get(&name)
+                        .is_some_and(|callee| callee.file == func.file) =>
+            {
+                let callee = &small[&name];
+                // The callee's locals are added after the caller's own
+                let offset = func.locals.len();
+                func.locals.extend(callee.locals.iter().cloned());
+                for (i, arg) in args.into_iter().enumerate() {
+                    inlined.push(Stmt::Assign(Local(offset + i), Rvalue::Use(arg)));
+                }
+                let mut body = callee.body.clone();
+                renumber_stmts(&mut body.stmts, offset);
+                renumber(&mut body.result, offset);
+                inlined.extend(body.stmts);
+                inlined.push(Stmt::Assign(dest, Rvalue::Use(body.result)));
+            }
+            Stmt::If {
+                dest,
+                condition,
+                then,
+                other,
+            } => {
+                let then = Block {
+                    stmts: inline_stmts(func, then.stmts, small),
+                    result: then.result,
+                };
+                let other = Block {
+                    stmts: inline_stmts(func, other.stmts, small),
+                    result: other.result,
+                };
+                inlined.push(Stmt::If {
+                    dest,
+                    condition,
+                    then,
+                    other,
+                });
+            }
+            Stmt::Each { var, max, body } => {
+                let body = inline_stmts(func, body, small);
+                inlined.push(Stmt::Each { var, max, body });
+            }
+            stmt => inlined.push(stmt),
+        }
+    }
+    inlined
+}
+
+/// Evaluates operations on constants at compile time and replaces uses of
+/// temporaries holding constants with the constant. Operations that would
+/// panic are left for the runtime to report.
+pub struct ConstantFold;
+
+impl Pass for ConstantFold {
+    fn name(&self) -> &'static str {
+        "constant-fold"
+    }
+
+    fn run(&self, program: &mut Program) {
+        for func in &mut program.funcs {
+            let mut constants = HashMap::new();
+            let stmts = mem::take(&mut func.body.stmts);
+            func.body.stmts = fold_stmts(func, stmts, &mut constants);
+            substitute(&mut func.body.result, &constants);
+        }
+    }
+}
+
+fn fold_stmts(
+    func: &Function,
+    stmts: Vec<Stmt>,
+    constants: &mut HashMap<Local, Operand>,
+) -> Vec<Stmt> {
+    let mut folded = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(dest, mut rvalue) => {
+                for operand in rvalue.operands_mut() {
+                    substitute(operand, constants);
+                }
+                if let Some(value) = fold_rvalue(func, &rvalue) {
+                    rvalue = Rvalue::Use(Operand::Const(value, func.local_type(dest).clone()));
+                }
+                // Only temporaries, variables can be assigned again
+                if let Rvalue::Use(operand @ Operand::Const(..)) = &rvalue {
+                    if func.locals[dest.0].name.is_none() {
+                        constants.insert(dest, operand.clone());
+                    }
+                }
+                folded.push(Stmt::Assign(dest, rvalue));
+            }
+            Stmt::If {
+                dest,
+                mut condition,
+                then,
+                other,
+            } => {
+                substitute(&mut condition, constants);
+                let fold_block = |block: Block, constants: &mut HashMap<_, _>| {
+                    let stmts = fold_stmts(func, block.stmts, constants);
+                    let mut result = block.result;
+                    substitute(&mut result, constants);
+                    Block { stmts, result }
+                };
+                if let Operand::Const(condition, _) = condition {
+                    // Only the branch that is taken is left
+                    let taken = if condition != 0 { then } else { other };
+                    let taken = fold_block(taken, constants);
+                    folded.extend(taken.stmts);
+                    if let Operand::Const(..) = &taken.result {
+                        constants.insert(dest, taken.result.clone());
+                    }
+                    folded.push(Stmt::Assign(dest, Rvalue::Use(taken.result)));
+                    continue;
+                }
+                folded.push(Stmt::If {
+                    dest,
+                    condition,
+                    then: fold_block(then, constants),
+                    other: fold_block(other, constants),
+                });
+            }
+            Stmt::Each { var, mut max, body } => {
+                substitute(&mut max, constants);
+                let body = fold_stmts(func, body, constants);
+                folded.push(Stmt::Each { var, max, body });
+            }
+        }
+    }
+    folded
+}
+
+/// The value of `rvalue`, if it only depends on constants and can't panic.
+fn fold_rvalue(func: &Function, rvalue: &Rvalue) -> Option<i64> {
+    match rvalue {
+        Rvalue::Binary(op, lhs @ Operand::Const(x, _), Operand::Const(y, _), _) => {
+            // Checked arithmetic is always checked in the MIR
+            operation(func.operand_type(lhs), *x, op, *y, true).ok()
+        }
+        Rvalue::Cast(Operand::Const(x, _), to) => Some(truncate(to, *x)),
+        _ => None,
+    }
+}
+
+fn substitute(operand: &mut Operand, constants: &HashMap<Local, Operand>) {
+    if let Operand::Local(local) = operand {
+        if let Some(constant) = constants.get(local) {
+            *operand = constant.clone();
+        }
+    }
+}
+
+/// Moves `#array#` out of loops that don't assign the array, along with the
+/// copies of variables it reads.
+pub struct HoistLen;
+
+impl Pass for HoistLen {
+    fn name(&self) -> &'static str {
+        "hoist-len"
+    }
+
+    fn run(&self, program: &mut Program) {
+        for func in &mut program.funcs {
+            let stmts = mem::take(&mut func.body.stmts);
+            func.body.stmts = hoist_stmts(func, stmts);
+        }
+    }
+}
+
+fn hoist_stmts(func: &Function, stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut hoisted = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Stmt::Each { var, max, body } => {
+                // Inner loops first, so their invariants can keep going
+                let body = hoist_stmts(func, body);
+                let mut assigned = HashSet::from([var]);
+                assigned_locals(&body, &mut assigned);
+
+                // Copies and lengths whose operands don't change in the loop
+                let mut invariant = HashSet::new();
+                for stmt in &body {
+                    if let Stmt::Assign(dest, rvalue @ (Rvalue::Use(_) | Rvalue::Len(_))) = stmt {
+                        let reads_invariant = rvalue.operands().into_iter().all(|operand| {
+                            !matches!(operand, Operand::Local(local) if assigned.contains(local))
+                        });
+                        if func.locals[dest.0].name.is_none() && reads_invariant {
+                            invariant.insert(*dest);
+                            assigned.remove(dest);
+                        }
+                    }
+                }
+
+                // Of those, the lengths and the copies they need
+                let mut needed = HashSet::new();
+                for stmt in body.iter().rev() {
+                    let Stmt::Assign(dest, rvalue) = stmt else {
+                        continue;
+                    };
+                    if invariant.contains(dest)
+                        && (matches!(rvalue, Rvalue::Len(_)) || needed.contains(dest))
+                    {
+                        needed.insert(*dest);
+                        for operand in rvalue.operands() {
+                            if let Operand::Local(local) = operand {
+                                needed.insert(*local);
+                            }
+                        }
+                    }
+                }
+
+                let (moved, body) = body.into_iter().partition::<Vec<_>, _>(|stmt| {
+                    matches!(stmt, Stmt::Assign(dest, _) if invariant.contains(dest) && needed.contains(dest))
+                });
+                hoisted.extend(moved);
+                hoisted.push(Stmt::Each { var, max, body });
+            }
+            Stmt::If {
+                dest,
+                condition,
+                then,
+                other,
+            } => hoisted.push(Stmt::If {
+                dest,
+                condition,
+                then: Block {
+                    stmts: hoist_stmts(func, then.stmts),
+                    result: then.result,
+                },
+                other: Block {
+                    stmts: hoist_stmts(func, other.stmts),
+                    result: other.result,
+                },
+            }),
+            stmt => hoisted.push(stmt),
+        }
+    }
+    hoisted
+}
+
+/// Removes assignments to locals that are never read, when computing the
+/// value has no other effect.
+pub struct DeadDefs;
+
+impl Pass for DeadDefs {
+    fn name(&self) -> &'static str {
+        "dead-defs"
+    }
+
+    fn run(&self, program: &mut Program) {
+        for func in &mut program.funcs {
+            // Removing one assignment can leave the locals it read unused
+            loop {
+                let mut read = HashSet::new();
+                read_locals(&func.body.stmts, &mut read);
+                read_operand(&func.body.result, &mut read);
+                if !remove_dead(&mut func.body.stmts, &read) {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn remove_dead(stmts: &mut Vec<Stmt>, read: &HashSet<Local>) -> bool {
+    let before = stmts.len();
+    stmts.retain(|stmt| match stmt {
+        Stmt::Assign(dest, rvalue) => read.contains(dest) || !rvalue.is_pure(),
+        _ => true,
+    });
+    let mut removed = stmts.len() != before;
+    for stmt in stmts {
+        match stmt {
+            Stmt::If { then, other, .. } => {
+                removed |= remove_dead(&mut then.stmts, read);
+                removed |= remove_dead(&mut other.stmts, read);
+            }
+            Stmt::Each { body, .. } => removed |= remove_dead(body, read),
+            Stmt::Assign(..) => {}
+        }
+    }
+    removed
+}
+
+fn read_locals(stmts: &[Stmt], read: &mut HashSet<Local>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(_, rvalue) => {
+                for operand in rvalue.operands() {
+                    read_operand(operand, read);
+                }
+            }
+            Stmt::If {
+                condition,
+                then,
+                other,
+                ..
+            } => {
+                read_operand(condition, read);
+                for block in [then, other] {
+                    read_locals(&block.stmts, read);
+                    read_operand(&block.result, read);
+                }
+            }
+            Stmt::Each { max, body, .. } => {
+                read_operand(max, read);
+                read_locals(body, read);
+            }
+        }
+    }
+}
+
+fn read_operand(operand: &Operand, read: &mut HashSet<Local>) {
+    if let Operand::Local(local) = operand {
+        read.insert(*local);
+    }
+}
+
+fn assigned_locals(stmts: &[Stmt], assigned: &mut HashSet<Local>) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(dest, _) => {
+                assigned.insert(*dest);
+            }
+            Stmt::If {
+                dest, then, other, ..
+            } => {
+                assigned.insert(*dest);
+                assigned_locals(&then.stmts, assigned);
+                assigned_locals(&other.stmts, assigned);
+            }
+            Stmt::Each { var, body, .. } => {
+                assigned.insert(*var);
+                assigned_locals(body, assigned);
+            }
+        }
+    }
+}
+
+fn stmt_count(stmts: &[Stmt]) -> usize {
+    stmts
+        .iter()
+        .map(|stmt| match stmt {
+            Stmt::Assign(..) => 1,
+            Stmt::If { then, other, .. } => 1 + stmt_count(&then.stmts) + stmt_count(&other.stmts),
+            Stmt::Each { body, .. } => 1 + stmt_count(body),
+        })
+        .sum()
+}
+
+/// Whether `stmts` call the function `name`.
+fn calls(stmts: &[Stmt], name: &str) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        Stmt::Assign(_, Rvalue::Call(callee, ..)) => callee == name,
+        Stmt::Assign(..) => false,
+        Stmt::If { then, other, .. } => calls(&then.stmts, name) || calls(&other.stmts, name),
+        Stmt::Each { body, .. } => calls(body, name),
+    })
+}
+
+/// Shifts every local in `stmts` up by `offset`.
+fn renumber_stmts(stmts: &mut [Stmt], offset: usize) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::Assign(dest, rvalue) => {
+                dest.0 += offset;
+                for operand in rvalue.operands_mut() {
+                    renumber(operand, offset);
+                }
+            }
+            Stmt::If {
+                dest,
+                condition,
+                then,
+                other,
+            } => {
+                dest.0 += offset;
+                renumber(condition, offset);
+                for block in [then, other] {
+                    renumber_stmts(&mut block.stmts, offset);
+                    renumber(&mut block.result, offset);
+                }
+            }
+            Stmt::Each { var, max, body } => {
+                var.0 += offset;
+                renumber(max, offset);
+                renumber_stmts(body, offset);
+            }
+        }
+    }
+}
+
+fn renumber(operand: &mut Operand, offset: usize) {
+    if let Operand::Local(local) = operand {
+        local.0 += offset;
+    }
+}
@@ -1,53 +1,104 @@
 use cranelift::{
-    codegen::{
-        dbg,
-        gimli::leb128::write,
-        ir::{stackslot::StackSize, types::I8},
-    },
+    codegen::ir::types::{I16, I32, I8},
     prelude::*,
 };
-use std::{any::Any, borrow::Borrow, collections::HashMap, fs::File, sync::Arc};
+use std::collections::{HashMap, HashSet};
 
 use cranelift::{
     codegen::{
-        control::ControlPlane,
-        ir::{types::I64, AbiParam, Function, Signature, UserFuncName},
-        isa::{self, CallConv, TargetIsa},
-        settings::{self, Configurable},
+        ir::{
+            types::I64, AbiParam, Endianness, Function, Signature, SourceLoc, UserFuncName,
+            ValueLabel,
+        },
+        isa::{CallConv, OwnedTargetIsa},
         Context,
     },
     frontend::{FunctionBuilder, FunctionBuilderContext},
 };
-use cranelift_module::{FuncId, Linkage, Module};
-use cranelift_object::{ObjectBuilder, ObjectModule, ObjectProduct};
+use cranelift_jit::JITModule;
+use cranelift_module::{DataDescription, DataId, FuncId, Linkage, Module};
+use cranelift_object::ObjectModule;
 use target_lexicon::Triple;
-
-use crate::parser::{self, Expr, Func};
-
-use self::types::{TypedExpr, TypedFunc, TypedValue};
-
+use tracing::{debug, info_span, trace};
+
+use crate::parser::{self, ExternFunc, Location};
+
+use self::backend::target_isa;
+pub use self::backend::Backend;
+use self::builtins::{builtins, runtime_functions, PANIC_DIVIDE_BY_ZERO, PANIC_OVERFLOW};
+use self::debug::DebugInfo;
+use self::types::TypedProgram;
+
+mod backend;
+pub mod builtins;
+mod debug;
+pub mod eval;
+pub mod fold;
+pub mod mir;
+pub mod runtime;
 pub mod types;
 
-macro_rules! core_fn {
-    ($name: expr, $functions: ident, $module: ident, $call_conv: ident) => {
-        let mut signature = Signature::new($call_conv);
-        signature.params.push(AbiParam::new(I64));
-        signature.returns.push(AbiParam::new(I64));
+/// Size in bytes of every array slot, including the leading length slot.
+const ELEMENT_SIZE: i64 = 8;
+
+#[derive(Debug, Clone, Default)]
+pub struct CompilerOptions {
+    /// Panic when `+`, `-` and `*` overflow instead of wrapping around.
+    pub checked_arithmetic: bool,
+    /// Keep a disassembly of every function in `Artifacts::asm`.
+    pub disassemble: bool,
+    pub opt_level: OptLevel,
+    /// Extra Cranelift settings as `(name, value)`, applied last so they can
+    /// override anything moistc sets itself.
+    pub cranelift_flags: Vec<(String, String)>,
+    /// Put DWARF debug info in the object file, so debuggers can map machine
+    /// code back to the source. Only ELF targets support it.
+    pub debug_info: bool,
+}
 
-        let fid = $module
-            .declare_function($name, Linkage::Import, &signature)
-            .unwrap();
-        $functions.insert($name.to_string(), fid);
-        println!("{}", $name.to_string());
-    };
+/// How hard Cranelift tries to optimize, matching its `opt_level` setting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    #[default]
+    None,
+    Speed,
+    SpeedAndSize,
 }
 
-pub struct Compiler {
-    module: ObjectModule,
-    isa: Arc<dyn TargetIsa>,
+/// Everything `Compiler::build` produces.
+pub struct Artifacts {
+    /// The relocatable object file, ready to be linked with the runtime.
+    pub object: Vec<u8>,
+    /// The mid-level IR of every function, after optimization.
+    pub mir: String,
+    /// The Cranelift IR of every function.
+    pub clif: String,
+    /// The machine code of every function, if `CompilerOptions::disassemble`
+    /// was set.
+    pub asm: String,
+}
+
+/// Lowers a typed program into any `Backend`: `ObjectModule` to `build` an
+/// object file, or `JITModule` to `run` it in memory.
+pub struct Compiler<M: Backend> {
+    module: M,
+    options: CompilerOptions,
     function_builder_ctx: FunctionBuilderContext,
     call_conv: CallConv,
     functions: HashMap<String, FuncId>,
+    /// Functions called from outside the module, which use the platform's C
+    /// calling convention. Everything else uses `tail`, so it can make tail
+    /// calls.
+    exports: HashSet<String>,
+    /// Read-only data objects emitted for constant array literals, keyed by
+    /// their encoded bytes so identical literals share a single symbol.
+    constants: HashMap<Vec<u8>, DataId>,
+    /// Collected as functions are compiled if `CompilerOptions::debug_info`
+    /// is set, and written once the object file is finished.
+    debug: Option<DebugInfo>,
+    mir: String,
+    clif: String,
+    asm: String,
 }
 
 // enum CompilerState {
@@ -55,58 +106,110 @@ pub struct Compiler {
 //     Compiled,
 // }
 
-impl Compiler {
-    pub fn new() -> Self {
-        let mut settings_builder = settings::builder();
-        settings_builder.enable("is_pic").unwrap();
-        let flags = settings::Flags::new(settings_builder);
-
-        let isa_builder = isa::lookup(Triple::host()).expect("Unsupported architecture");
-        let isa = isa_builder.finish(flags).unwrap();
-        let call_conv = isa.default_call_conv();
-
-        let obj_builder = ObjectBuilder::new(
-            isa.clone(),
-            "main",
-            cranelift_module::default_libcall_names(),
-        );
-        let mut obj_module = ObjectModule::new(obj_builder.unwrap());
-
-        let mut functions = HashMap::new();
-
-        // Add print function
-
-        core_fn!("printint", functions, obj_module, call_conv);
-        core_fn!("printchar", functions, obj_module, call_conv);
-        core_fn!("printcharln", functions, obj_module, call_conv);
-        core_fn!("printintln", functions, obj_module, call_conv);
-        core_fn!("readchar", functions, obj_module, call_conv);
+impl<M: Backend> Compiler<M> {
+    /// A compiler for the machine moistc runs on.
+    pub fn new(options: CompilerOptions) -> Result<Self, String> {
+        let isa = target_isa(&Triple::host(), M::PIC, &options)?;
+        Ok(Self::with_isa(options, isa))
+    }
 
-        Self {
-            module: obj_module,
-            isa,
+    fn with_isa(options: CompilerOptions, isa: OwnedTargetIsa) -> Self {
+        let module = M::for_isa(isa);
+        // The ISA knows the target's C calling convention
+        let call_conv = module.isa().default_call_conv();
+        let debug = options.debug_info.then(|| DebugInfo::new(module.isa()));
+        let mut compiler = Self {
+            debug,
+            module,
+            options,
             call_conv,
             function_builder_ctx: FunctionBuilderContext::new(),
-            functions,
+            functions: HashMap::new(),
+            exports: HashSet::from(["main".to_string()]),
+            constants: HashMap::new(),
+            mir: String::new(),
+            clif: String::new(),
+            asm: String::new(),
+        };
+        for func in builtins().iter().chain(&runtime_functions()) {
+            compiler.declare_extern(func);
         }
+        compiler
     }
 
-    pub fn compile_program(&mut self, funcs: Vec<TypedFunc>) -> Context {
-        let mut ctx = self.module.make_context(); //for_function(self.main_function.clone()); //ew ugly clone please remove
-        for (i, func) in funcs.iter().enumerate() {
-            let mut signature = Signature::new(self.call_conv);
-            signature.returns.push(AbiParam::new(I64));
+    /// Imports a function defined outside of the program with a signature
+    /// matching its declared types.
+    fn declare_extern(&mut self, func: &ExternFunc) {
+        let mut signature = Signature::new(self.call_conv);
+        for arg in &func.args {
+            signature
+                .params
+                .push(AbiParam::new(clif_type(&arg.arg_type)));
+        }
+        if let Some(ret) = &func.ret {
+            signature.returns.push(AbiParam::new(clif_type(ret)));
+        }
+        let fid = self
+            .module
+            .declare_function(&func.name, Linkage::Import, &signature)
+            .unwrap();
+        self.functions.insert(func.name.clone(), fid);
+    }
 
-            let mut args = HashMap::new();
-            for (idx, arg) in func.args.iter().enumerate() {
-                signature.params.push(AbiParam::new(I64));
+    /// Compiles every function of `program`, or fails with one line per
+    /// operation on constants that would always panic.
+    pub fn compile_program(&mut self, program: TypedProgram) -> Result<Context, String> {
+        // Cranelift can't declare the same name twice with other signatures,
+        // and the builtins and anything defined before are declared already
+        for func in &program.externs {
+            if self.functions.contains_key(&func.name)
+                || program
+                    .funcs
+                    .iter()
+                    .any(|defined| defined.name == func.name)
+            {
+                return Err(format!(
+                    "extern funion {} clashes with another function of the same name",
+                    func.name
+                ));
+            }
+            self.declare_extern(func);
+        }
 
-                let var = Variable::new(idx);
-                dbg!(&var);
-                args.insert(arg.name.clone(), var);
+        let program = info_span!("fold")
+            .in_scope(|| fold::fold_program(program, self.options.checked_arithmetic))
+            .map_err(|diagnostics| {
+                diagnostics
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+        let mut program = mir::lower_program(&program, self.options.checked_arithmetic);
+        mir::optimize(&mut program, self.options.opt_level);
+        self.mir += &program.to_string();
+
+        let _span = info_span!("emit").entered();
+
+        // Everything is declared up front, so functions can call themselves
+        // and each other in any order
+        let mut declarations = Vec::new();
+        for func in &program.funcs {
+            let exported = self.exports.contains(&func.name);
+            let call_conv = if exported {
+                self.call_conv
+            } else {
+                CallConv::Tail
+            };
+            let mut signature = Signature::new(call_conv);
+            signature.returns.push(AbiParam::new(clif_type(&func.ret)));
+            for arg in &func.locals[..func.arg_count] {
+                signature
+                    .params
+                    .push(AbiParam::new(clif_type(&arg.local_type)));
             }
 
-            let linkage = if func.name == String::from("main") {
+            let linkage = if exported {
                 Linkage::Export
             } else {
                 Linkage::Local
@@ -115,9 +218,13 @@ impl Compiler {
                 .module
                 .declare_function(&func.name, linkage, &signature)
                 .unwrap();
+            self.functions.insert(func.name.clone(), fid);
+            declarations.push((fid, signature));
+        }
 
-            // dbg!(&signature);
-
+        let mut ctx = self.module.make_context(); //for_function(self.main_function.clone()); //ew ugly clone please remove
+        for (i, (func, (fid, signature))) in program.funcs.iter().zip(declarations).enumerate() {
+            let _span = info_span!("function", name = %func.name).entered();
             let mut function = Function::with_name_signature(
                 UserFuncName::user(0, i.try_into().unwrap()),
                 signature,
@@ -134,22 +241,29 @@ impl Compiler {
 
             let function_compiler = FunctionCompiler::new(
                 function_builder,
-                func.clone(),
+                func,
                 &mut self.module,
+                &mut self.constants,
                 self.functions.clone(),
-                args,
+                self.debug.is_some(),
             );
-            function_compiler.compile(entry);
-
-            self.functions.insert(func.name.clone(), fid);
+            let locations = function_compiler.compile(entry);
 
-            println!("{}", function.clone());
+            self.clif += &format!("{}\n", function.display());
             ctx.func = function;
+            ctx.set_disasm(self.options.disassemble);
 
             self.module.define_function(fid, &mut ctx).unwrap();
+            if let Some(vcode) = ctx.compiled_code().and_then(|code| code.vcode.as_ref()) {
+                self.asm += &format!("{}:\n{}\n", func.name, vcode);
+            }
+            if let Some(debug) = &mut self.debug {
+                let exported = self.exports.contains(&func.name);
+                debug.add_function(self.module.isa(), fid, func, exported, &ctx, &locations);
+            }
             ctx.clear();
         }
-        ctx
+        Ok(ctx)
     }
 
     // fn compile_function(&mut self, func: &Func, mut function: Function) {
@@ -159,297 +273,670 @@ impl Compiler {
     // fn finalize(&mut self) -> Context {
     //     ctx
     // }
+}
 
-    // pub fn exec(&mut self, funcs: Vec<Func>) {
-    //     let mut ctx = self.compile_program(funcs);
-    //
-    //     dbg!(&ctx.func.name);
-    //
-    //     let code = ctx
-    //         .compile(self.isa.borrow(), &mut ControlPlane::default())
-    //         .unwrap();
-    //
-    //     let code_buffer = code.code_buffer();
-    //     let mut buffer = memmap2::MmapOptions::new()
-    //         .len(code_buffer.len())
-    //         .map_anon()
-    //         .unwrap();
-    //
-    //     buffer.copy_from_slice(code_buffer);
-    //
-    //     let buffer = buffer.make_exec().unwrap();
-    //
-    //     let out = unsafe {
-    //         let code_fn: unsafe extern "sysv64" fn() -> usize =
-    //             std::mem::transmute(buffer.as_ptr());
-    //
-    //         code_fn()
-    //     };
-    //     // let outfile = File::create("./main.o").unwrap();
-    //     // ob
-    //     println!("💦: 5 {:b}", 5);
-    //     println!("💦: {} {:b}", out, out);
-    // }
+impl Compiler<ObjectModule> {
+    /// A compiler writing object files for `target`, which doesn't have to be
+    /// the machine moistc runs on.
+    pub fn for_target(options: CompilerOptions, target: &Triple) -> Result<Self, String> {
+        let isa = target_isa(target, ObjectModule::PIC, &options)?;
+        Ok(Self::with_isa(options, isa))
+    }
 
-    pub fn build(mut self, funcs: Vec<TypedFunc>) {
-        let _ = self.compile_program(funcs);
-        let res = self.module.finish();
-        let outfile = File::create("./main.o").unwrap();
-        res.object.write_stream(outfile).unwrap();
+    pub fn build(mut self, program: TypedProgram) -> Result<Artifacts, String> {
+        self.compile_program(program)?;
+        let _span = info_span!("object").entered();
+        let mut product = self.module.finalize(&self.functions)?;
+        if let Some(debug) = self.debug {
+            debug.write(&mut product)?;
+        }
+        Ok(Artifacts {
+            object: product.emit().unwrap(),
+            mir: self.mir,
+            clif: self.clif,
+            asm: self.asm,
+        })
     }
 }
 
-struct FunctionCompiler<'a> {
+impl Compiler<JITModule> {
+    /// Compiles `program` in memory and calls its `main`, returning what it
+    /// returns.
+    pub fn run(mut self, program: TypedProgram) -> Result<i64, String> {
+        let main = program
+            .funcs
+            .iter()
+            .find(|func| func.name == "main")
+            .ok_or("Program has no main function")?
+            .clone();
+        if !main.args.is_empty() {
+            return Err("main can't take arguments when it is run in memory".to_string());
+        }
+        self.define(program)?;
+        Ok(narrow(
+            self.module.finalize(&self.functions)?.call_main(),
+            &main.func_type.ret,
+        ))
+    }
+
+    /// Makes the function `name` callable with `call` once it is defined.
+    pub fn export(&mut self, name: &str) {
+        self.exports.insert(name.to_string());
+    }
+
+    /// Compiles `program` in memory next to everything defined before, which
+    /// it can call.
+    pub fn define(&mut self, program: TypedProgram) -> Result<(), String> {
+        self.compile_program(program).map(|_| ())
+    }
+
+    /// Calls the defined function `name`, which has to be exported, take no
+    /// arguments and return a `ret`.
+    pub fn call(&mut self, name: &str, ret: &types::Type) -> i64 {
+        self.module.flush().unwrap();
+        let code = self.module.get_finalized_function(self.functions[name]);
+        narrow(backend::call_function(code), ret)
+    }
+}
+
+/// Whether `stmts` build an array on the stack, which every array literal
+/// with an element only known at runtime does.
+fn builds_stack_arrays(stmts: &[mir::Stmt]) -> bool {
+    stmts.iter().any(|stmt| match stmt {
+        mir::Stmt::Assign(_, mir::Rvalue::Array(elements)) => elements
+            .iter()
+            .any(|element| matches!(element, mir::Operand::Local(_))),
+        mir::Stmt::Assign(..) => false,
+        mir::Stmt::If { then, other, .. } => {
+            builds_stack_arrays(&then.stmts) || builds_stack_arrays(&other.stmts)
+        }
+        mir::Stmt::Each { body, .. } => builds_stack_arrays(body),
+    })
+}
+
+/// Sign or zero extends the low bits of a returned register that a narrower
+/// integer `ret` defines.
+fn narrow(value: i64, ret: &types::Type) -> i64 {
+    match ret {
+        types::Type::Int8 => value as i8 as i64,
+        types::Type::Int16 => value as i16 as i64,
+        types::Type::Int32 => value as i32 as i64,
+        types::Type::UInt8 => value as u8 as i64,
+        types::Type::UInt16 => value as u16 as i64,
+        types::Type::UInt32 => value as u32 as i64,
+        _ => value,
+    }
+}
+
+struct FunctionCompiler<'a, M: Module> {
     builder: FunctionBuilder<'a>,
-    func: TypedFunc,
-    variables: HashMap<String, Variable>,
+    func: &'a mir::Function,
+    /// Where the body starts, after the arguments are defined. Tail calls
+    /// of the function to itself jump back here.
+    body_block: Block,
+    /// Whether the function builds arrays in its own stack frame, which a
+    /// tail call would free or overwrite while the callee still reads them.
+    stack_arrays: bool,
     functions: HashMap<String, FuncId>,
-    module: &'a mut ObjectModule,
+    module: &'a mut M,
+    constants: &'a mut HashMap<Vec<u8>, DataId>,
+    /// The source locations instructions are tagged with, numbered by their
+    /// `SourceLoc`. Only kept when compiling with debug info.
+    locations: Option<Vec<Location>>,
 }
 
-impl<'a> FunctionCompiler<'a> {
+impl<'a, M: Module> FunctionCompiler<'a, M> {
     pub fn new(
-        builder: FunctionBuilder<'a>,
-        func: TypedFunc,
-        module: &'a mut ObjectModule,
+        mut builder: FunctionBuilder<'a>,
+        func: &'a mir::Function,
+        module: &'a mut M,
+        constants: &'a mut HashMap<Vec<u8>, DataId>,
         functions: HashMap<String, FuncId>,
-        variables: HashMap<String, Variable>,
+        debug_info: bool,
     ) -> Self {
-        dbg!(&variables);
+        debug!(locals = func.locals.len(), "compiling");
+        if debug_info {
+            // Track which values the variables are in
+            builder.func.collect_debug_info();
+        }
         Self {
+            locations: debug_info.then(Vec::new),
+            body_block: builder.create_block(),
+            stack_arrays: builds_stack_arrays(&func.body.stmts),
             builder,
             func,
-            variables,
             functions,
             module,
+            constants,
         }
     }
 
-    pub fn compile(mut self, block: Block) {
+    /// Compiles the function, returning the source locations its
+    /// instructions refer to.
+    pub fn compile(mut self, block: Block) -> Vec<Location> {
+        self.set_location(self.func.location);
         self.builder.append_block_params_for_function_params(block);
-        for (i, (_, arg)) in self.variables.iter().enumerate() {
-            self.builder.declare_var(*arg, I64);
-            if let Some(param) = self.builder.block_params(block).get(i) {
-                self.builder.def_var(*arg, *param);
-            }
+        // Every local is the Cranelift variable with the same number
+        for (i, local) in self.func.locals.iter().enumerate() {
+            self.builder
+                .declare_var(Variable::new(i), clif_type(&local.local_type));
         }
-        dbg!(self.builder.block_params(block));
+        for i in 0..self.func.arg_count {
+            let param = self.builder.block_params(block)[i];
+            self.define(mir::Local(i), param);
+        }
+
+        self.builder.ins().jump(self.body_block, &[]);
+        self.builder.switch_to_block(self.body_block);
 
-        let returning = self.compile_expr(self.func.body.clone());
-        // dbg!(ret);
-        dbg!(returning);
-        self.builder.ins().return_(&[returning]);
+        let func = self.func;
+        if let Some(returning) = self.compile_block(&func.body.stmts, &func.body.result, true) {
+            self.builder.ins().return_(&[returning]);
+        }
         self.builder.seal_all_blocks();
 
         self.builder.finalize();
+        self.locations.unwrap_or_default()
+    }
+
+    /// Tags the instructions that follow with `location`.
+    fn set_location(&mut self, location: Location) {
+        if let Some(locations) = &mut self.locations {
+            self.builder
+                .set_srcloc(SourceLoc::new(locations.len() as u32));
+            locations.push(location);
+        }
     }
 
-    fn compile_expr(&mut self, expr: TypedExpr) -> Value {
-        match expr {
-            TypedExpr::Len(arr) => {
-                let arr = self.compile_expr(*arr);
-                self.builder.ins().load(I64, MemFlags::new(), arr, 0)
+    fn define(&mut self, local: mir::Local, value: Value) {
+        self.builder.def_var(Variable::new(local.0), value);
+        if self.func.locals[local.0].name.is_some() {
+            self.builder
+                .set_val_label(value, ValueLabel::from_u32(local.0 as u32));
+        }
+    }
+
+    fn operand(&mut self, operand: &mir::Operand) -> Value {
+        match operand {
+            mir::Operand::Const(x, const_type) => {
+                let const_type = clif_type(const_type);
+                // Narrow constants are given as their low bits only
+                let mask = match const_type.bits() {
+                    64 => -1,
+                    bits => (1i64 << bits) - 1,
+                };
+                self.builder.ins().iconst(const_type, x & mask)
             }
-            TypedExpr::Value(r#type, TypedValue::Number(x)) => {
-                self.builder.ins().iconst(I64, i64::from(x))
+            mir::Operand::Local(local) => self.builder.use_var(Variable::new(local.0)),
+        }
+    }
+
+    /// Compiles `stmts` and returns the value of `result`. In tail position,
+    /// a call producing the result is made a tail call where possible, and
+    /// `None` is returned if the block ended with one.
+    fn compile_block(
+        &mut self,
+        stmts: &[mir::Stmt],
+        result: &mir::Operand,
+        tail: bool,
+    ) -> Option<Value> {
+        let mir::Operand::Local(mut tail_local) = result else {
+            self.compile_stmts(stmts);
+            return Some(self.operand(result));
+        };
+        // Look through copies of the result, which inlining leaves behind
+        let mut end = stmts.len();
+        while let Some(mir::Stmt::Assign(dest, mir::Rvalue::Use(mir::Operand::Local(source)))) =
+            stmts[..end].last()
+        {
+            if *dest != tail_local {
+                break;
             }
-            TypedExpr::Value(r#type, TypedValue::Bool(x)) => {
-                self.builder.ins().iconst(I64, if x { 1 } else { 0 })
+            tail_local = *source;
+            end -= 1;
+        }
+        let Some((last, init)) = stmts[..end].split_last().filter(|_| tail) else {
+            self.compile_stmts(stmts);
+            return Some(self.operand(result));
+        };
+
+        self.compile_stmts(init);
+        match last {
+            mir::Stmt::Assign(dest, mir::Rvalue::Call(name, args, location))
+                if *dest == tail_local && self.compile_tail_call(name, args, *location) =>
+            {
+                return None;
             }
-            TypedExpr::Value(r#type, TypedValue::Array(x)) => {
-                let slot = self.construct_array(x);
-                self.builder.ins().stack_addr(I64, slot, 0)
+            mir::Stmt::If {
+                dest,
+                condition,
+                then,
+                other,
+            } if *dest == tail_local => {
+                if !self.compile_if(*dest, condition, then, other, true) {
+                    return None;
+                }
             }
-            TypedExpr::Index {
-                target,
-                index,
-                contained_type,
-            } => {
-                let target = self.compile_expr(*target);
-                let index = self.compile_expr(*index);
-                let value_size = self.builder.ins().iconst(I64, 64);
+            last => self.compile_stmts(std::slice::from_ref(last)),
+        }
+        self.compile_stmts(&stmts[end..]);
+        Some(self.operand(result))
+    }
+
+    /// Ends the function by calling `name` without growing the stack, if it
+    /// can: calls to itself jump back to the start of the body, and calls
+    /// between functions using the `tail` convention become `return_call`s.
+    fn compile_tail_call(&mut self, name: &str, args: &[mir::Operand], location: Location) -> bool {
+        // Any array passed on could be one of this frame's
+        if self.stack_arrays
+            && args
+                .iter()
+                .any(|arg| matches!(self.func.operand_type(arg), types::Type::Array(_)))
+        {
+            return false;
+        }
+        if name == self.func.name {
+            self.set_location(location);
+            let args = args
+                .iter()
+                .map(|arg| self.operand(arg))
+                .collect::<Vec<Value>>();
+            for (i, arg) in args.into_iter().enumerate() {
+                self.define(mir::Local(i), arg);
+            }
+            self.builder.ins().jump(self.body_block, &[]);
+            return true;
+        }
+
+        let Some(callee) = self.functions.get(name).copied() else {
+            return false;
+        };
+        let callee_signature = &self
+            .module
+            .declarations()
+            .get_function_decl(callee)
+            .signature;
+        let signature = &self.builder.func.signature;
+        if signature.call_conv != CallConv::Tail
+            || callee_signature.call_conv != CallConv::Tail
+            || callee_signature.returns != signature.returns
+        {
+            return false;
+        }
+        self.set_location(location);
+        let func = self.module.declare_func_in_func(callee, self.builder.func);
+        let args = args
+            .iter()
+            .map(|arg| self.operand(arg))
+            .collect::<Vec<Value>>();
+        self.builder.ins().return_call(func, &args);
+        true
+    }
+
+    fn compile_stmts(&mut self, stmts: &[mir::Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                mir::Stmt::Assign(local, rvalue) => {
+                    let value = self.compile_rvalue(self.func.local_type(*local), rvalue);
+                    self.define(*local, value);
+                }
+                mir::Stmt::If {
+                    dest,
+                    condition,
+                    then,
+                    other,
+                } => {
+                    self.compile_if(*dest, condition, then, other, false);
+                }
+                mir::Stmt::Each { var, max, body } => self.compile_each(*var, max, body),
+            }
+        }
+    }
+
+    fn compile_rvalue(&mut self, dest_type: &types::Type, rvalue: &mir::Rvalue) -> Value {
+        trace!(?rvalue);
+        match rvalue {
+            mir::Rvalue::Use(operand) => self.operand(operand),
+            mir::Rvalue::Len(array) => {
+                let array = self.operand(array);
+                self.builder.ins().load(I64, MemFlags::new(), array, 0)
+            }
+            mir::Rvalue::Array(elements) => {
+                if let Some(data) = self.encode_constant_array(elements) {
+                    let data = self.constant_data(data);
+                    let global = self.module.declare_data_in_func(data, self.builder.func);
+                    self.builder.ins().symbol_value(I64, global)
+                } else {
+                    let slot = self.construct_array(elements);
+                    self.builder.ins().stack_addr(I64, slot, 0)
+                }
+            }
+            mir::Rvalue::Index(target, index) => {
+                let target = self.operand(target);
+                let index = self.operand(index);
+                let value_size = self.builder.ins().iconst(I64, ELEMENT_SIZE);
                 let offset = self.builder.ins().imul(value_size, index);
                 let offset = self.builder.ins().iadd(offset, value_size);
                 let stack_ptr = self.builder.ins().iadd(offset, target);
 
-                self.builder.ins().load(I64, MemFlags::new(), stack_ptr, 0)
-            }
-            TypedExpr::Ident(r#type, ident) => {
-                dbg!(&self.variables);
-                let variable = self
-                    .variables
-                    .get(&ident)
-                    .expect(&format!("Found undefined variable {}", ident));
-                println!("{}: {:?}", &ident, &variable);
-                dbg!(self.builder.use_var(*variable))
+                self.builder
+                    .ins()
+                    .load(clif_type(dest_type), MemFlags::new(), stack_ptr, 0)
             }
-            TypedExpr::Operation(r#type, lhs, op, rhs) => {
-                let lhs = self.compile_expr(*lhs);
-                let rhs = self.compile_expr(*rhs);
-                let ins = self.builder.ins();
+            mir::Rvalue::Binary(op, lhs, rhs, location) => {
+                self.set_location(*location);
+                let unsigned = self.func.operand_type(lhs).is_unsigned();
+                let lhs = self.operand(lhs);
+                let rhs = self.operand(rhs);
                 match op {
-                    parser::Op::Add => ins.iadd(lhs, rhs),
-                    parser::Op::Sub => ins.isub(lhs, rhs),
-                    parser::Op::Mul => ins.imul(lhs, rhs),
-                    parser::Op::Div => ins.sdiv(lhs, rhs),
-                    _ => self.compile_comparsion(op, lhs, rhs),
+                    parser::Op::Add | parser::Op::Sub | parser::Op::Mul => {
+                        self.compile_checked_arithmetic(op.clone(), unsigned, lhs, rhs, *location)
+                    }
+                    parser::Op::WrappingAdd => self.builder.ins().iadd(lhs, rhs),
+                    parser::Op::WrappingSub => self.builder.ins().isub(lhs, rhs),
+                    parser::Op::WrappingMul => self.builder.ins().imul(lhs, rhs),
+                    parser::Op::Div | parser::Op::Rem => {
+                        self.compile_division(op.clone(), unsigned, lhs, rhs, *location)
+                    }
+                    parser::Op::BitAnd => self.builder.ins().band(lhs, rhs),
+                    parser::Op::BitOr => self.builder.ins().bor(lhs, rhs),
+                    parser::Op::BitXor => self.builder.ins().bxor(lhs, rhs),
+                    parser::Op::Shl => self.builder.ins().ishl(lhs, rhs),
+                    parser::Op::Shr if unsigned => self.builder.ins().ushr(lhs, rhs),
+                    parser::Op::Shr => self.builder.ins().sshr(lhs, rhs),
+                    _ => self.compile_comparsion(op.clone(), unsigned, lhs, rhs),
                 }
             }
-            TypedExpr::Def { ident, value } => {
-                let value = self.compile_expr(*value);
-                let variable = Variable::from_u32(self.variables.keys().len() as u32);
-                self.builder.declare_var(variable, I64);
-                self.builder.def_var(variable, value);
-                self.variables.insert(ident, variable);
-                value
+            mir::Rvalue::Cast(value, to) => {
+                let unsigned = self.func.operand_type(value).is_unsigned();
+                let value = self.operand(value);
+                let from = self.builder.func.dfg.value_type(value);
+                let to = clif_type(to);
+                if to.bits() < from.bits() {
+                    self.builder.ins().ireduce(to, value)
+                } else if to.bits() == from.bits() {
+                    value
+                } else if unsigned {
+                    self.builder.ins().uextend(to, value)
+                } else {
+                    self.builder.ins().sextend(to, value)
+                }
             }
-            TypedExpr::FunctionCall(r#type, name, args) => {
-                dbg!(&self.functions);
-
+            mir::Rvalue::Call(name, args, location) => {
+                self.set_location(*location);
                 let func = self.module.declare_func_in_func(
                     *self
                         .functions
-                        .get(&name)
-                        .expect(&format!("Undefined function {}", name)),
+                        .get(name)
+                        .unwrap_or_else(|| panic!("Undefined function {}", name)),
                     self.builder.func,
                 );
 
                 let args = args
                     .iter()
-                    .map(|arg| {
-                        dbg!(&arg);
-                        dbg!(self.compile_expr(*arg.clone()))
-                    })
+                    .map(|arg| self.operand(arg))
                     .collect::<Vec<Value>>();
                 let ret = self.builder.ins().call(func, &args);
-                let recieved = self.builder.inst_results(ret);
-                dbg!(recieved);
-                recieved[0]
-            }
-            TypedExpr::Then { lhs, rhs } => {
-                let _ = self.compile_expr(*lhs);
-                self.compile_expr(*rhs)
+                match self.builder.inst_results(ret).first() {
+                    Some(value) => *value,
+                    None => self.builder.ins().iconst(clif_type(dest_type), 0),
+                }
             }
-            TypedExpr::Each {
-                body,
-                ident,
-                target,
-            } => {
-                let max = self.compile_expr(*target);
-
-                let header_block = self.builder.create_block();
-                let body_block = self.builder.create_block();
-                let exit_block = self.builder.create_block();
-                let init = self.builder.ins().iconst(I64, 1);
-
-                self.builder.ins().jump(header_block, &[init]);
-
-                self.builder.append_block_param(header_block, I64);
-                self.builder.append_block_param(body_block, I64);
-
-                self.builder.switch_to_block(header_block);
-                let i = self.builder.block_params(header_block)[0];
-                let cond = self.builder.ins().icmp(IntCC::SignedGreaterThan, i, max);
-                self.builder
-                    .ins()
-                    .brif(cond, exit_block, &[], body_block, &[i]);
-                self.builder.switch_to_block(body_block);
-                let var = Variable::new(self.variables.keys().len());
-                self.builder.declare_var(var, I64);
-                self.builder
-                    .def_var(var, self.builder.block_params(body_block)[0]);
-                self.variables.insert(ident, var);
-                self.compile_expr(*body);
-                let i = self.builder.use_var(var);
-                let i = self.builder.ins().iadd(i, init);
-                self.builder.ins().jump(header_block, &[i]);
-
-                self.builder.switch_to_block(exit_block);
-
-                self.builder.seal_block(header_block);
-                self.builder.seal_block(body_block);
-                self.builder.seal_block(exit_block);
+        }
+    }
 
-                self.builder.ins().iconst(I64, 0)
+    /// Returns whether anything comes after the `if`, which isn't the case
+    /// when both branches end in tail calls.
+    fn compile_if(
+        &mut self,
+        dest: mir::Local,
+        condition: &mir::Operand,
+        then: &mir::Block,
+        other: &mir::Block,
+        tail: bool,
+    ) -> bool {
+        let condition_value = self.operand(condition);
+
+        let then_block = self.builder.create_block();
+        let else_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
+        self.builder
+            .append_block_param(merge_block, clif_type(self.func.local_type(dest)));
+
+        self.builder
+            .ins()
+            .brif(condition_value, then_block, &[], else_block, &[]);
+
+        let mut merged = false;
+        for (block, branch) in [(then_block, then), (else_block, other)] {
+            self.builder.switch_to_block(block);
+            self.builder.seal_block(block);
+            if let Some(value) = self.compile_block(&branch.stmts, &branch.result, tail) {
+                self.builder.ins().jump(merge_block, &[value]);
+                merged = true;
             }
-            TypedExpr::IfThen {
-                condition,
-                then,
-                other,
-            } => {
-                let condition_value = self.compile_expr(*condition);
-
-                let then_block = self.builder.create_block();
-                let else_block = self.builder.create_block();
-                let merge_block = self.builder.create_block();
-
-                self.builder.append_block_param(merge_block, I64);
-
-                self.builder
-                    .ins()
-                    .brif(condition_value, then_block, &[], else_block, &[]);
-
-                self.builder.switch_to_block(then_block);
-                self.builder.seal_block(then_block);
-                let then_return = self.compile_expr(*then);
+        }
+        if !merged {
+            return false;
+        }
 
-                self.builder.ins().jump(merge_block, &[then_return]);
+        self.builder.switch_to_block(merge_block);
+        self.builder.seal_block(merge_block);
 
-                self.builder.switch_to_block(else_block);
-                self.builder.seal_block(else_block);
-                let else_return = self.compile_expr(*other);
+        let phi = self.builder.block_params(merge_block)[0];
+        self.define(dest, phi);
+        true
+    }
 
-                self.builder.ins().jump(merge_block, &[else_return]);
+    fn compile_each(&mut self, var: mir::Local, max: &mir::Operand, body: &[mir::Stmt]) {
+        let max = self.operand(max);
+
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+        let init = self.builder.ins().iconst(I64, 1);
+
+        self.builder.ins().jump(header_block, &[init]);
+
+        self.builder.append_block_param(header_block, I64);
+        self.builder.append_block_param(body_block, I64);
+
+        self.builder.switch_to_block(header_block);
+        let i = self.builder.block_params(header_block)[0];
+        let cond = self.builder.ins().icmp(IntCC::SignedGreaterThan, i, max);
+        self.builder
+            .ins()
+            .brif(cond, exit_block, &[], body_block, &[i]);
+        self.builder.switch_to_block(body_block);
+        let i = self.builder.block_params(body_block)[0];
+        self.define(var, i);
+        self.compile_stmts(body);
+        let i = self.builder.use_var(Variable::new(var.0));
+        let i = self.builder.ins().iadd(i, init);
+        self.builder.ins().jump(header_block, &[i]);
+
+        self.builder.switch_to_block(exit_block);
+
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(body_block);
+        self.builder.seal_block(exit_block);
+    }
 
-                self.builder.switch_to_block(merge_block);
+    fn compile_checked_arithmetic(
+        &mut self,
+        op: parser::Op,
+        unsigned: bool,
+        lhs: Value,
+        rhs: Value,
+        location: Location,
+    ) -> Value {
+        let ins = self.builder.ins();
+        let (result, overflow) = match (&op, unsigned) {
+            (parser::Op::Add, false) => ins.sadd_overflow(lhs, rhs),
+            (parser::Op::Add, true) => ins.uadd_overflow(lhs, rhs),
+            (parser::Op::Sub, false) => ins.ssub_overflow(lhs, rhs),
+            (parser::Op::Sub, true) => ins.usub_overflow(lhs, rhs),
+            (parser::Op::Mul, false) => ins.smul_overflow(lhs, rhs),
+            (parser::Op::Mul, true) => ins.umul_overflow(lhs, rhs),
+            _ => unreachable!("{:?} has no overflow check", op),
+        };
+        self.panic_if(overflow, PANIC_OVERFLOW, location);
+        result
+    }
 
-                self.builder.seal_block(merge_block);
+    /// Division and remainder always check for a zero divisor and, for
+    /// signed division, the one quotient that doesn't fit (`MIN / -1`),
+    /// instead of letting the hardware fault.
+    fn compile_division(
+        &mut self,
+        op: parser::Op,
+        unsigned: bool,
+        lhs: Value,
+        rhs: Value,
+        location: Location,
+    ) -> Value {
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+        self.panic_if(is_zero, PANIC_DIVIDE_BY_ZERO, location);
+        match (op, unsigned) {
+            (parser::Op::Rem, true) => return self.builder.ins().urem(lhs, rhs),
+            (parser::Op::Rem, false) => return self.builder.ins().srem(lhs, rhs),
+            (_, true) => return self.builder.ins().udiv(lhs, rhs),
+            _ => {}
+        }
 
-                let phi = self.builder.block_params(merge_block)[0];
+        let value_type = self.builder.func.dfg.value_type(lhs);
+        let bits = value_type.bits();
+        let mask = if bits == 64 { -1 } else { (1i64 << bits) - 1 };
+        let min = self
+            .builder
+            .ins()
+            .iconst(value_type, i64::MIN >> (64 - bits) & mask);
+        let minus_one = self.builder.ins().iconst(value_type, mask);
+        let is_min = self.builder.ins().icmp(IntCC::Equal, lhs, min);
+        let is_minus_one = self.builder.ins().icmp(IntCC::Equal, rhs, minus_one);
+        let overflow = self.builder.ins().band(is_min, is_minus_one);
+        self.panic_if(overflow, PANIC_OVERFLOW, location);
+        self.builder.ins().sdiv(lhs, rhs)
+    }
 
-                phi
-            }
-        }
+    /// Branches to a call of the runtime panic function `name` when
+    /// `condition` is set, and carries on in a fresh block otherwise.
+    fn panic_if(&mut self, condition: Value, name: &str, location: Location) {
+        let panic_block = self.builder.create_block();
+        let continue_block = self.builder.create_block();
+        self.builder.set_cold_block(panic_block);
+        self.builder
+            .ins()
+            .brif(condition, panic_block, &[], continue_block, &[]);
+
+        self.builder.switch_to_block(panic_block);
+        self.builder.seal_block(panic_block);
+        let func = self
+            .module
+            .declare_func_in_func(self.functions[name], self.builder.func);
+        // The file name is passed like any string literal
+        let name = self
+            .func
+            .file
+            .chars()
+            .map(|ch| mir::Operand::Const(ch as i64, types::Type::Char))
+            .collect::<Vec<_>>();
+        let name = self.encode_constant_array(&name).unwrap();
+        let name = self.constant_data(name);
+        let name = self.module.declare_data_in_func(name, self.builder.func);
+        let file = self.builder.ins().symbol_value(I64, name);
+        let line = self.builder.ins().iconst(I64, location.line as i64);
+        let column = self.builder.ins().iconst(I64, location.column as i64);
+        self.builder.ins().call(func, &[file, line, column]);
+        // The runtime exits, so this is never reached
+        self.builder.ins().trap(TrapCode::unwrap_user(1));
+
+        self.builder.switch_to_block(continue_block);
+        self.builder.seal_block(continue_block);
     }
 
-    fn compile_comparsion(&mut self, op: parser::Op, lhs: Value, rhs: Value) -> Value {
-        let comp = match op {
-            parser::Op::Ge => self
-                .builder
-                .ins()
-                .icmp(IntCC::SignedGreaterThanOrEqual, lhs, rhs),
-            parser::Op::Le => self
-                .builder
-                .ins()
-                .icmp(IntCC::SignedLessThanOrEqual, lhs, rhs),
-            parser::Op::Gt => self.builder.ins().icmp(IntCC::SignedGreaterThan, lhs, rhs),
-            parser::Op::Lt => self.builder.ins().icmp(IntCC::SignedLessThan, lhs, rhs),
-            parser::Op::Eq => self.builder.ins().icmp(IntCC::Equal, lhs, rhs),
-            parser::Op::Neq => self.builder.ins().icmp(IntCC::NotEqual, lhs, rhs),
-            _ => self.builder.ins().iconst(I8, 0),
+    fn compile_comparsion(
+        &mut self,
+        op: parser::Op,
+        unsigned: bool,
+        lhs: Value,
+        rhs: Value,
+    ) -> Value {
+        let cond = match op {
+            parser::Op::Ge => IntCC::SignedGreaterThanOrEqual,
+            parser::Op::Le => IntCC::SignedLessThanOrEqual,
+            parser::Op::Gt => IntCC::SignedGreaterThan,
+            parser::Op::Lt => IntCC::SignedLessThan,
+            parser::Op::Eq => IntCC::Equal,
+            parser::Op::Neq => IntCC::NotEqual,
+            _ => return self.builder.ins().iconst(I64, 0),
         };
+        let cond = if unsigned { cond.unsigned() } else { cond };
+        let comp = self.builder.ins().icmp(cond, lhs, rhs);
         self.builder.ins().sextend(I64, comp)
     }
 
-    fn construct_array(&mut self, x: Vec<TypedExpr>) -> codegen::ir::StackSlot {
+    fn construct_array(&mut self, elements: &[mir::Operand]) -> codegen::ir::StackSlot {
         let slot = self.builder.create_sized_stack_slot(StackSlotData::new(
             StackSlotKind::ExplicitSlot,
-            64 * (x.len() as u32),
+            (ELEMENT_SIZE as u32) * (elements.len() as u32 + 1),
+            ELEMENT_SIZE.trailing_zeros() as u8,
         ));
-        let len = self.builder.ins().iconst(I64, x.len() as i64);
+        let len = self.builder.ins().iconst(I64, elements.len() as i64);
         self.builder.ins().stack_store(len, slot, 0);
-        for i in 0..x.len() {
-            let value = self.compile_expr(x[i].clone());
+        for (i, element) in elements.iter().enumerate() {
+            let value = self.operand(element);
             self.builder
                 .ins()
-                .stack_store(value, slot, ((i + 1) as i32) * 64);
+                .stack_store(value, slot, ((i + 1) as i32) * ELEMENT_SIZE as i32);
         }
         slot
     }
+
+    /// Lays out an array literal the same way `construct_array` does, or
+    /// returns `None` if any element has to be computed at runtime.
+    fn encode_constant_array(&self, elements: &[mir::Operand]) -> Option<Vec<u8>> {
+        let endianness = self.module.isa().endianness();
+        // Each element is stored at the start of its slot at its own width
+        let encode = |value: i64, size: usize| match endianness {
+            Endianness::Little => value.to_le_bytes()[..size].to_vec(),
+            Endianness::Big => value.to_be_bytes()[8 - size..].to_vec(),
+        };
+        let mut data = encode(elements.len() as i64, 8);
+        for element in elements {
+            let mir::Operand::Const(value, element_type) = element else {
+                return None;
+            };
+            let size = clif_type(element_type).bytes() as usize;
+            data.extend(encode(*value, size));
+            data.resize(data.len() + ELEMENT_SIZE as usize - size, 0);
+        }
+        Some(data)
+    }
+
+    fn constant_data(&mut self, data: Vec<u8>) -> DataId {
+        if let Some(id) = self.constants.get(&data) {
+            return *id;
+        }
+        let id = self.module.declare_anonymous_data(false, false).unwrap();
+        let mut description = DataDescription::new();
+        description.define(data.clone().into_boxed_slice());
+        description.set_align(ELEMENT_SIZE as u64);
+        self.module.define_data(id, &description).unwrap();
+        self.constants.insert(data, id);
+        id
+    }
 }
 
-fn drop_size_of(contained_type: types::Type) -> i64 {
-    todo!()
+/// The Cranelift type a value of `t` is held in. Everything that isn't a
+/// sized integer lives in a full 64 bit register.
+fn clif_type(t: &types::Type) -> codegen::ir::Type {
+    match t {
+        types::Type::Int8 | types::Type::UInt8 => I8,
+        types::Type::Int16 | types::Type::UInt16 => I16,
+        types::Type::Int32 | types::Type::UInt32 => I32,
+        _ => I64,
+    }
 }
 
 // mod prelude {
@@ -0,0 +1,99 @@
+//! Rust versions of the functions in `core.c`, linked into programs run by
+//! the JIT so they don't need a C compiler.
+
+use std::{
+    io::{self, BufRead, Write},
+    process,
+};
+
+use super::builtins::{PANIC_DIVIDE_BY_ZERO, PANIC_OVERFLOW};
+
+/// Every builtin and runtime function by the name generated code calls it.
+pub fn symbols() -> Vec<(&'static str, *const u8)> {
+    vec![
+        ("printint", printint as *const u8),
+        ("printintln", printintln as *const u8),
+        ("printchar", printchar as *const u8),
+        ("printcharln", printcharln as *const u8),
+        ("readchar", readchar as *const u8),
+        (PANIC_OVERFLOW, panic_overflow as *const u8),
+        (PANIC_DIVIDE_BY_ZERO, panic_divide_by_zero as *const u8),
+    ]
+}
+
+/// Writes out anything the program printed that is still buffered.
+pub fn flush() {
+    let _ = io::stdout().flush();
+}
+
+extern "C" fn printint(x: i64) -> i64 {
+    print!("{x}");
+    x
+}
+
+extern "C" fn printintln(x: i64) -> i64 {
+    println!("{x}");
+    x
+}
+
+extern "C" fn printchar(c: i64) -> i64 {
+    print!("{}", to_char(c));
+    c
+}
+
+extern "C" fn printcharln(c: i64) -> i64 {
+    println!("{}", to_char(c));
+    c
+}
+
+/// Reads the next byte of stdin that isn't whitespace, like `scanf(" %c")`,
+/// or returns -1 at the end of input.
+extern "C" fn readchar() -> i64 {
+    let mut stdin = io::stdin().lock();
+    loop {
+        let Ok(buffer) = stdin.fill_buf() else {
+            return -1;
+        };
+        let Some(&byte) = buffer.first() else {
+            return -1;
+        };
+        stdin.consume(1);
+        if !byte.is_ascii_whitespace() {
+            return byte as i8 as i64;
+        }
+    }
+}
+
+extern "C" fn panic_overflow(file: *const i64, line: i64, column: i64) {
+    panic_at(file, line, column, "arithmetic overflow");
+}
+
+extern "C" fn panic_divide_by_zero(file: *const i64, line: i64, column: i64) {
+    panic_at(file, line, column, "division by zero");
+}
+
+fn panic_at(file: *const i64, line: i64, column: i64, message: &str) -> ! {
+    flush();
+    // SAFETY: generated code passes a string literal, its length followed by
+    // that many chars.
+    let file = unsafe {
+        let chars = std::slice::from_raw_parts(file.add(1), *file as usize);
+        chars.iter().map(|&c| to_char(c)).collect::<String>()
+    };
+    // Code typed into the REPL has no file
+    let file = if file.is_empty() {
+        String::new()
+    } else {
+        format!("{file}:")
+    };
+    eprintln!("panic at {file}{line}:{column}: {message}");
+    process::exit(101);
+}
+
+/// Chars are unicode scalar values, anything else prints as `�`.
+pub fn to_char(c: i64) -> char {
+    u32::try_from(c)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
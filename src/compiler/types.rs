@@ -1,43 +1,102 @@
 use core::panic;
-use std::{collections::HashMap, fmt::write, mem};
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    mem,
+};
 
-use crate::parser::{Arg, Expr, Func, Op, Value};
+use tracing::{debug, info_span};
+
+use super::builtins::builtins;
+use crate::parser::{Arg, Expr, ExternFunc, Func, Location, Op, Program, Value};
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Type {
+    /// 64 bit signed integer, also written `Int64`.
     Int,
+    Int8,
+    Int16,
+    Int32,
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
     Float,
     Bool,
+    Char,
     Array(Box<Type>),
 }
 
+impl Type {
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self,
+            Type::Int
+                | Type::Int8
+                | Type::Int16
+                | Type::Int32
+                | Type::UInt8
+                | Type::UInt16
+                | Type::UInt32
+                | Type::UInt64
+        )
+    }
+
+    /// Whether values of this type compare, divide and widen as unsigned.
+    pub fn is_unsigned(&self) -> bool {
+        matches!(
+            self,
+            Type::UInt8 | Type::UInt16 | Type::UInt32 | Type::UInt64 | Type::Char | Type::Bool
+        )
+    }
+}
+
+/// Writes the type the way it is spelled in source.
+impl Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Array(inner) => write!(f, "Array<{inner}>"),
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
 // i hate this but i cant think of how to get rid of this enum
 #[derive(Debug, Clone, PartialEq)]
 pub enum TypedValue {
-    Number(i32),
+    Number(i64),
     Bool(bool),
+    Char(char),
     Array(Vec<TypedExpr>),
 }
 
+#[derive(Debug, Clone)]
+pub struct TypedProgram {
+    pub funcs: Vec<TypedFunc>,
+    pub externs: Vec<ExternFunc>,
+}
+
 #[derive(Debug, Clone)]
 pub struct TypedFunc {
     pub name: String,
     pub args: Vec<Arg>,
     pub func_type: FuncType,
     pub body: TypedExpr,
+    pub location: Location,
+    pub file: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct FuncType {
-    args: Vec<Type>,
-    ret: Type,
+    pub args: Vec<Type>,
+    pub ret: Type,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TypedExpr {
     Value(Type, TypedValue),
     Ident(Type, String),
-    Operation(Type, Box<TypedExpr>, Op, Box<TypedExpr>),
+    Operation(Type, Box<TypedExpr>, Op, Box<TypedExpr>, Location),
     Def {
         ident: String,
         value: Box<TypedExpr>,
@@ -46,7 +105,7 @@ pub enum TypedExpr {
         lhs: Box<TypedExpr>,
         rhs: Box<TypedExpr>,
     },
-    FunctionCall(Type, String, Vec<Box<TypedExpr>>),
+    FunctionCall(Type, String, Vec<TypedExpr>, Location),
     IfThen {
         condition: Box<TypedExpr>,
         then: Box<TypedExpr>,
@@ -63,6 +122,10 @@ pub enum TypedExpr {
         target: Box<TypedExpr>,
     },
     Len(Box<TypedExpr>),
+    Cast {
+        value: Box<TypedExpr>,
+        to: Type,
+    },
 }
 
 pub struct TypeGenerator {
@@ -71,58 +134,62 @@ pub struct TypeGenerator {
 
 impl TypeGenerator {
     pub fn new() -> Self {
-        let mut functions = HashMap::new();
-        functions.insert(
-            "printchar".into(),
-            FuncType {
-                args: vec![Type::Int],
-                ret: Type::Int,
-            },
-        );
-        functions.insert(
-            "printint".into(),
-            FuncType {
-                args: vec![Type::Int],
-                ret: Type::Int,
-            },
-        );
-        functions.insert(
-            "printcharln".into(),
-            FuncType {
-                args: vec![Type::Int],
-                ret: Type::Int,
-            },
-        );
-        functions.insert(
-            "printintln".into(),
-            FuncType {
-                args: vec![Type::Int],
-                ret: Type::Int,
-            },
-        );
-        functions.insert(
-            "readchar".into(),
+        let mut type_generator = Self {
+            functions: HashMap::new(),
+        };
+        for func in builtins() {
+            type_generator.declare_extern(&func);
+        }
+        type_generator
+    }
+
+    fn declare_extern(&mut self, func: &ExternFunc) {
+        self.functions.insert(
+            func.name.clone(),
             FuncType {
-                args: vec![Type::Int],
-                ret: Type::Int,
+                args: func.args.iter().map(|arg| arg.arg_type.clone()).collect(),
+                // Calling a function that returns nothing evaluates to 0
+                ret: func.ret.clone().unwrap_or(Type::Int),
             },
         );
-        Self { functions }
     }
-    pub fn generate_types(&mut self, funcs: Vec<Func>) -> Vec<TypedFunc> {
-        funcs
+
+    pub fn generate_types(&mut self, program: Program) -> TypedProgram {
+        let _span = info_span!("typecheck").entered();
+        for func in &program.externs {
+            self.declare_extern(func);
+        }
+        for func in &program.funcs {
+            if let Some(ret) = &func.ret {
+                self.functions.insert(
+                    func.name.clone(),
+                    FuncType {
+                        args: func.args.iter().map(|arg| arg.arg_type.clone()).collect(),
+                        ret: ret.clone(),
+                    },
+                );
+            }
+        }
+        let funcs = program
+            .funcs
             .iter()
             .map(|func| {
+                let _span = info_span!("function", name = %func.name).entered();
                 let mut variables = HashMap::new();
                 for arg in &func.args {
                     variables.insert(arg.name.clone(), arg.arg_type.clone());
                 }
                 let func_type = self.generate_function_type(func.clone(), variables);
+                debug!(ret = %func_type.func_type.ret, "typed");
                 self.functions
                     .insert(func.name.clone(), func_type.func_type.clone());
                 func_type
             })
-            .collect::<Vec<TypedFunc>>()
+            .collect::<Vec<TypedFunc>>();
+        TypedProgram {
+            funcs,
+            externs: program.externs,
+        }
     }
 
     fn generate_function_type(
@@ -137,14 +204,26 @@ impl TypeGenerator {
             .collect::<Vec<Type>>();
         let mut func = func.clone();
         let body = self.expression_type(func.body, &mut variables);
+        if let Some(ret) = &func.ret {
+            if *ret != get_type(&body) {
+                panic!(
+                    "{} is declared to return {:?} but returns {:?}",
+                    func.name,
+                    ret,
+                    get_type(&body)
+                );
+            }
+        }
         TypedFunc {
             body: body.clone(),
             name: mem::take(&mut func.name),
             args: mem::take(&mut func.args),
             func_type: FuncType {
                 args,
-                ret: get_type(body),
+                ret: get_type(&body),
             },
+            location: func.location,
+            file: mem::take(&mut func.file),
         }
     }
 
@@ -152,7 +231,7 @@ impl TypeGenerator {
         match body {
             Expr::Len(x) => TypedExpr::Len(Box::new(self.expression_type(*x, variables))),
             Expr::Value(value) => TypedExpr::Value(
-                value_type(self.type_value(value.clone(), variables)),
+                value_type(&self.type_value(value.clone(), variables)),
                 self.type_value(value, variables),
             ),
             Expr::Each {
@@ -161,10 +240,9 @@ impl TypeGenerator {
                 target,
             } => {
                 let target = self.expression_type(*target, variables);
-                let target_type = get_type(target.clone());
+                let target_type = get_type(&target);
                 if target_type != Type::Int {
-                    println!("Expected {:?} got {:?}", Type::Int, target_type);
-                    panic!("Each target was not of type int");
+                    panic!("Each target was not of type int, got {target_type:?}");
                 }
                 variables.insert(ident.clone(), Type::Int);
                 TypedExpr::Each {
@@ -176,37 +254,72 @@ impl TypeGenerator {
             Expr::Ident(ident) => TypedExpr::Ident(
                 variables
                     .get(&ident)
-                    .expect(&format!("Undefined variable, {ident}"))
+                    .unwrap_or_else(|| panic!("Undefined variable, {ident}"))
                     .clone(),
                 ident,
             ),
             Expr::Index { target, index } => {
                 let target_type = self.expression_type(*target, variables);
                 let index_type = self.expression_type(*index, variables);
-                match (get_type(target_type.clone()), get_type(index_type.clone())) {
+                match (get_type(&target_type), get_type(&index_type)) {
                     (Type::Array(contained), Type::Int) => TypedExpr::Index {
                         target: Box::new(target_type),
                         index: Box::new(index_type),
                         contained_type: *contained,
                     },
-                    (invalid_arr, invalid_int) => {
-                        println!(
-                            "Expected Array<_> got: {:?} and expected Int got: {:?}",
-                            invalid_arr, invalid_int
-                        );
-                        panic!("Types in index expression are not valid!");
-                    }
+                    (invalid_arr, invalid_int) => panic!(
+                        "Types in index expression are not valid! Expected Array<_> got: {:?} and expected Int got: {:?}",
+                        invalid_arr, invalid_int
+                    ),
+                }
+            }
+            Expr::Operation(lhs, op, rhs, location) => {
+                let operand_type = self.force_identical(*lhs.clone(), *rhs.clone(), variables);
+                if matches!(
+                    op,
+                    Op::Rem | Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr
+                ) && !operand_type.is_integer()
+                {
+                    panic!("{:?} expects integers, got {:?}", op, operand_type);
+                }
+                TypedExpr::Operation(
+                    match op {
+                        Op::Add
+                        | Op::Sub
+                        | Op::Mul
+                        | Op::Div
+                        | Op::Rem
+                        | Op::WrappingAdd
+                        | Op::WrappingSub
+                        | Op::WrappingMul
+                        | Op::BitAnd
+                        | Op::BitOr
+                        | Op::BitXor
+                        | Op::Shl
+                        | Op::Shr => operand_type,
+                        _ => Type::Bool,
+                    },
+                    Box::new(self.expression_type(*lhs, variables)),
+                    op,
+                    Box::new(self.expression_type(*rhs, variables)),
+                    location,
+                )
+            }
+            Expr::Cast { value, to } => {
+                let value = self.expression_type(*value, variables);
+                let from = get_type(&value);
+                let castable = |t: &Type| t.is_integer() || *t == Type::Char || *t == Type::Bool;
+                if !(castable(&from) && (to.is_integer() || to == Type::Char)) {
+                    panic!("Cannot cast {:?} to {:?}", from, to);
+                }
+                TypedExpr::Cast {
+                    value: Box::new(value),
+                    to,
                 }
             }
-            Expr::Operation(lhs, op, rhs) => TypedExpr::Operation(
-                self.force_identical(lhs.clone(), rhs.clone(), variables),
-                Box::new(self.expression_type(*lhs, variables)),
-                op,
-                Box::new(self.expression_type(*rhs, variables)),
-            ),
             Expr::Def { ident, value } => {
                 let var_type = self.expression_type(*value, variables);
-                variables.insert(ident.clone(), get_type(var_type.clone()));
+                variables.insert(ident.clone(), get_type(&var_type));
                 TypedExpr::Def {
                     ident,
                     value: Box::new(var_type),
@@ -220,34 +333,41 @@ impl TypeGenerator {
                     rhs: Box::new(self.expression_type(*rhs, variables)),
                 }
             }
-            Expr::FunctionCall(name, args) => {
-                println!("{:?}", self.functions);
+            Expr::FunctionCall(name, args, location) => {
                 let function = self
                     .functions
                     .get(&name)
-                    .expect(&format!("Undefined function, {}", name));
+                    .unwrap_or_else(|| panic!("Undefined function, {}", name));
                 let ret = function.ret.clone();
                 let fn_args = function.args.clone();
+                if args.len() != fn_args.len() {
+                    panic!(
+                        "Mismatched number of arguments, {} expects {} but got {}",
+                        name,
+                        fn_args.len(),
+                        args.len()
+                    );
+                }
                 if !args.iter().enumerate().all(|(i, arg)| {
                     *fn_args.get(i).expect("Mismatched number of arguments")
-                        == get_type(self.expression_type(*arg.clone(), variables))
+                        == get_type(&self.expression_type(arg.clone(), variables))
                 }) {
-                    println!(
-                        "function: {}, passed in: {:?}, expected: {:?}",
+                    panic!(
+                        "Function arguments did not match, function: {}, passed in: {:?}, expected: {:?}",
                         name,
                         args.iter()
-                            .map(|arg| { get_type(self.expression_type(*arg.clone(), variables)) })
+                            .map(|arg| get_type(&self.expression_type(arg.clone(), variables)))
                             .collect::<Vec<Type>>(),
                         fn_args
                     );
-                    panic!("Function arguments did not match");
                 }
                 TypedExpr::FunctionCall(
                     ret,
                     name,
                     args.iter()
-                        .map(|arg| Box::new(self.expression_type(*arg.clone(), variables)))
-                        .collect::<Vec<Box<TypedExpr>>>(),
+                        .map(|arg| self.expression_type(arg.clone(), variables))
+                        .collect::<Vec<TypedExpr>>(),
+                    location,
                 )
             }
             Expr::IfThen {
@@ -256,10 +376,10 @@ impl TypeGenerator {
                 ref other,
             } => {
                 let condition = self.expression_type(*condition, variables);
-                if (get_type(condition.clone()) != Type::Bool) {
+                if get_type(&condition) != Type::Bool {
                     panic!("Condition is not of type Bool");
                 }
-                self.force_identical(then.clone(), other.clone(), variables);
+                self.force_identical(*then.clone(), *other.clone(), variables);
                 TypedExpr::IfThen {
                     condition: Box::new(condition),
                     then: Box::new(self.expression_type(*then.clone(), variables)),
@@ -271,39 +391,24 @@ impl TypeGenerator {
 
     fn force_identical(
         &mut self,
-        lhs: Box<Expr>,
-        rhs: Box<Expr>,
+        lhs: Expr,
+        rhs: Expr,
         variables: &mut HashMap<String, Type>,
     ) -> Type {
-        let lhs = get_type(self.expression_type(*lhs, variables));
-        let rhs = get_type(self.expression_type(*rhs, variables));
+        let lhs = get_type(&self.expression_type(lhs, variables));
+        let rhs = get_type(&self.expression_type(rhs, variables));
         if lhs == rhs {
             lhs
         } else {
-            println!("lhs: {:#?}, rhs: {:#?}", lhs, rhs);
-            panic!("The types of lhs and rhs are not equal");
-        }
-    }
-
-    fn string_to_type(&mut self, type_name: &str) -> Type {
-        match type_name {
-            "Int" => Type::Int,
-            "Float" => Type::Float,
-            "Bool" => Type::Bool,
-            x => {
-                if (x.starts_with("Array<")) {
-                    Type::Array(Box::new(self.string_to_type(&x[6..(x.len() - 1)])))
-                } else {
-                    panic!("Unknown type, {}", x)
-                }
-            }
+            panic!("The types of lhs and rhs are not equal, lhs: {lhs:?}, rhs: {rhs:?}");
         }
     }
 
     fn type_value(&mut self, value: Value, variables: &mut HashMap<String, Type>) -> TypedValue {
         match value {
-            Value::Number(x) => TypedValue::Number(x),
+            Value::Number(x, _) => TypedValue::Number(x),
             Value::Bool(x) => TypedValue::Bool(x),
+            Value::Char(x) => TypedValue::Char(x),
             Value::Array(x) => TypedValue::Array(
                 x.iter()
                     .map(|el| self.expression_type(el.clone(), variables))
@@ -313,38 +418,28 @@ impl TypeGenerator {
     }
 }
 
-fn get_type(expr: TypedExpr) -> Type {
+pub fn get_type(expr: &TypedExpr) -> Type {
     match expr {
         TypedExpr::Len(_) => Type::Int,
-        TypedExpr::Value(r#type, _) => r#type,
-        TypedExpr::Ident(r#type, _) => r#type,
-        TypedExpr::Operation(r#type, _, _, _) => r#type,
-        TypedExpr::Def { ident, value } => get_type(*value),
-        TypedExpr::Then { lhs, rhs } => get_type(*rhs),
-        TypedExpr::FunctionCall(r#type, _, _) => r#type,
-        TypedExpr::IfThen {
-            condition,
-            then,
-            other,
-        } => get_type(*then),
-        TypedExpr::Index {
-            target,
-            index,
-            contained_type,
-        } => contained_type,
-        TypedExpr::Each {
-            body,
-            ident,
-            target,
-        } => Type::Int,
+        TypedExpr::Value(r#type, _) => r#type.clone(),
+        TypedExpr::Ident(r#type, _) => r#type.clone(),
+        TypedExpr::Operation(r#type, ..) => r#type.clone(),
+        TypedExpr::Def { value, .. } => get_type(value),
+        TypedExpr::Then { rhs, .. } => get_type(rhs),
+        TypedExpr::FunctionCall(r#type, ..) => r#type.clone(),
+        TypedExpr::IfThen { then, .. } => get_type(then),
+        TypedExpr::Index { contained_type, .. } => contained_type.clone(),
+        TypedExpr::Each { .. } => Type::Int,
+        TypedExpr::Cast { to, .. } => to.clone(),
     }
 }
-fn value_type(value: TypedValue) -> Type {
+fn value_type(value: &TypedValue) -> Type {
     match value {
         TypedValue::Number(_) => Type::Int,
         TypedValue::Bool(_) => Type::Bool,
+        TypedValue::Char(_) => Type::Char,
         TypedValue::Array(inner) => Type::Array(Box::new(get_type(
-            inner.get(0).expect("Unable to infer array type").clone(),
+            inner.first().expect("Unable to infer array type"),
         ))),
     }
 }
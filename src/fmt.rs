@@ -0,0 +1,352 @@
+use std::fmt::{self, Display};
+
+use crate::{
+    compiler::types::{get_type, Type, TypedExpr, TypedFunc, TypedValue},
+    parser::{Arg, Expr, ExternFunc, Func, Op, Program, Value},
+};
+
+/// Prints `program` back out as source in the canonical style: tabs for
+/// indentation and one link of a then-chain per line. `use` declarations
+/// come first, then extern declarations, followed by the functions.
+pub fn format_program(program: &Program) -> String {
+    let mut formatted = String::new();
+    for module in &program.uses {
+        formatted += &format!("use {}\n", module.path.join("/"));
+    }
+    if !program.uses.is_empty() && (!program.externs.is_empty() || !program.funcs.is_empty()) {
+        formatted.push('\n');
+    }
+    for func in &program.externs {
+        formatted += &format_extern(func);
+    }
+    if !program.externs.is_empty() && !program.funcs.is_empty() {
+        formatted.push('\n');
+    }
+    for func in &program.funcs {
+        formatted += &format_func(func);
+    }
+    formatted
+}
+
+fn format_extern(func: &ExternFunc) -> String {
+    match &func.ret {
+        Some(ret) => format!(
+            "extern funion {}[{}] -> {}\n",
+            func.name,
+            format_args(&func.args),
+            ret
+        ),
+        None => format!("extern funion {}[{}]\n", func.name, format_args(&func.args)),
+    }
+}
+
+fn format_func(func: &Func) -> String {
+    let ret = match &func.ret {
+        Some(ret) => format!(" -> {ret}"),
+        None => String::new(),
+    };
+    format!(
+        "{}funion {}[{}]{} (\n\t{}\n)\n",
+        if func.public { "pub " } else { "" },
+        func.name,
+        format_args(&func.args),
+        ret,
+        format_expr(&func.body, 1)
+    )
+}
+
+/// Prints a type checked function like `format_func`, with its return type
+/// and the type of every identifier, call, operation, index, `if` and array
+/// after a `:`. Literals are only annotated when they aren't the type they'd
+/// have in source.
+pub fn format_typed_func(func: &TypedFunc) -> String {
+    format!(
+        "funion {}[{}] -> {} (\n\t{}\n)\n",
+        func.name,
+        format_args(&func.args),
+        func.func_type.ret,
+        format_typed_expr(&func.body, 1)
+    )
+}
+
+impl Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_expr(self, 0))
+    }
+}
+
+impl Display for TypedExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&format_typed_expr(self, 0))
+    }
+}
+
+fn format_args(args: &[Arg]) -> String {
+    args.iter()
+        .map(|arg| format!("{}: {}", arg.name, arg.arg_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats `expr`, indenting any lines after the first by `indent` tabs.
+fn format_expr(expr: &Expr, indent: usize) -> String {
+    match expr {
+        Expr::Value(value) => format_value(value, indent),
+        Expr::Ident(ident) => ident.clone(),
+        Expr::Operation(lhs, op, rhs, _) => {
+            let rhs = match **rhs {
+                Expr::Ident(_) | Expr::Value(Value::Number(..)) => {
+                    format!(" {}", format_expr(rhs, indent))
+                }
+                // The grammar only allows a parenthesised rhs right after the
+                // operator, ` (` would be lexed as the start of a function body
+                _ => format!("({})", format_expr(rhs, indent)),
+            };
+            format!("{} {}{}", format_expr(lhs, indent), symbol(op), rhs)
+        }
+        Expr::Def { ident, value } => format!("({} is {})", ident, format_expr(value, indent)),
+        Expr::Then { .. } => {
+            let mut links = Vec::new();
+            let mut rest = expr;
+            while let Expr::Then { lhs, rhs } = rest {
+                links.push(format_expr(lhs, indent));
+                rest = rhs;
+            }
+            links.push(format_expr(rest, indent));
+            let newline = format!("\n{}", "\t".repeat(indent));
+            format!(
+                "{}{}{}",
+                links.join(&format!(".{newline}")),
+                newline,
+                ";".repeat(links.len() - 1)
+            )
+        }
+        Expr::FunctionCall(name, args, _) => format!("{}[{}]", name, comma_separated(args, indent)),
+        Expr::IfThen {
+            condition,
+            then,
+            other,
+        } => format!(
+            "if {} then {} else {} end",
+            format_expr(condition, indent),
+            format_expr(then, indent),
+            format_expr(other, indent)
+        ),
+        Expr::Index { target, index } => format!(
+            "{}{{{}}}",
+            format_expr(target, indent),
+            format_expr(index, indent)
+        ),
+        Expr::Each {
+            body,
+            ident,
+            target,
+        } => {
+            let target = format_expr(target, indent);
+            let body = format_expr(body, indent + 1);
+            let target = if target.starts_with('(') {
+                target
+            } else {
+                format!(" {target}")
+            };
+            if body.contains('\n') || body.starts_with('(') {
+                let tabs = "\t".repeat(indent);
+                format!("each {ident} in{target} do\n{tabs}\t{body}\n{tabs}end")
+            } else {
+                format!("each {ident} in{target} do {body} end")
+            }
+        }
+        Expr::Len(value) => format!("#{}#", format_expr(value, indent)),
+        Expr::Cast { value, to } => format!("{} as {}", format_expr(value, indent), to),
+    }
+}
+
+fn format_typed_expr(expr: &TypedExpr, indent: usize) -> String {
+    let typed = |expr: String, value_type: &Type| format!("{expr}: {value_type}");
+    match expr {
+        TypedExpr::Value(value_type, value) => match value {
+            TypedValue::Number(x) if *value_type == Type::Int => x.to_string(),
+            TypedValue::Number(x) => typed(x.to_string(), value_type),
+            TypedValue::Bool(x) => x.to_string(),
+            TypedValue::Char(ch) => format!("'{}'", escape(*ch, '\'')),
+            TypedValue::Array(elements) => {
+                let chars = elements
+                    .iter()
+                    .map(|element| match element {
+                        TypedExpr::Value(_, TypedValue::Char(ch)) => Some(escape(*ch, '"')),
+                        _ => None,
+                    })
+                    .collect::<Option<String>>();
+                match chars {
+                    Some(chars) if !elements.is_empty() => format!("\"{chars}\""),
+                    _ => typed(
+                        format!(
+                            "{{{}}}",
+                            elements
+                                .iter()
+                                .map(|element| format_typed_expr(element, indent))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        value_type,
+                    ),
+                }
+            }
+        },
+        TypedExpr::Ident(ident_type, ident) => typed(ident.clone(), ident_type),
+        TypedExpr::Operation(result_type, lhs, op, rhs, _) => typed(
+            format!(
+                "({} {} {})",
+                format_typed_expr(lhs, indent),
+                symbol(op),
+                format_typed_expr(rhs, indent)
+            ),
+            result_type,
+        ),
+        TypedExpr::Def { ident, value } => {
+            format!("({} is {})", ident, format_typed_expr(value, indent))
+        }
+        TypedExpr::Then { .. } => {
+            let mut links = Vec::new();
+            let mut rest = expr;
+            while let TypedExpr::Then { lhs, rhs } = rest {
+                links.push(format_typed_expr(lhs, indent));
+                rest = rhs;
+            }
+            links.push(format_typed_expr(rest, indent));
+            let newline = format!("\n{}", "\t".repeat(indent));
+            format!(
+                "{}{}{}",
+                links.join(&format!(".{newline}")),
+                newline,
+                ";".repeat(links.len() - 1)
+            )
+        }
+        TypedExpr::FunctionCall(ret, name, args, _) => typed(
+            format!(
+                "{}[{}]",
+                name,
+                args.iter()
+                    .map(|arg| format_typed_expr(arg, indent))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ret,
+        ),
+        TypedExpr::IfThen {
+            condition,
+            then,
+            other,
+        } => typed(
+            format!(
+                "if {} then {} else {} end",
+                format_typed_expr(condition, indent),
+                format_typed_expr(then, indent),
+                format_typed_expr(other, indent)
+            ),
+            &get_type(expr),
+        ),
+        TypedExpr::Index {
+            target,
+            index,
+            contained_type,
+        } => typed(
+            format!(
+                "{}{{{}}}",
+                format_typed_expr(target, indent),
+                format_typed_expr(index, indent)
+            ),
+            contained_type,
+        ),
+        TypedExpr::Each {
+            body,
+            ident,
+            target,
+        } => {
+            let tabs = "\t".repeat(indent);
+            format!(
+                "each {ident} in {} do\n{tabs}\t{}\n{tabs}end",
+                format_typed_expr(target, indent),
+                format_typed_expr(body, indent + 1)
+            )
+        }
+        TypedExpr::Len(value) => format!("#{}#", format_typed_expr(value, indent)),
+        TypedExpr::Cast { value, to } => {
+            format!("({} as {})", format_typed_expr(value, indent), to)
+        }
+    }
+}
+
+/// Joins expressions with `, `, leaving out the space before a `(` since
+/// ` (` only lexes as the start of a function body.
+fn comma_separated(exprs: &[Expr], indent: usize) -> String {
+    let mut joined = String::new();
+    for (i, expr) in exprs.iter().enumerate() {
+        let expr = format_expr(expr, indent);
+        if i > 0 {
+            joined += if expr.starts_with('(') { "," } else { ", " };
+        }
+        joined += &expr;
+    }
+    joined
+}
+
+fn format_value(value: &Value, indent: usize) -> String {
+    match value {
+        Value::Number(_, text) => text.clone(),
+        Value::Bool(x) => x.to_string(),
+        Value::Char(ch) => format!("'{}'", escape(*ch, '\'')),
+        Value::Array(elements) => {
+            let chars = elements
+                .iter()
+                .map(|element| match element {
+                    Expr::Value(Value::Char(ch)) => Some(escape(*ch, '"')),
+                    _ => None,
+                })
+                .collect::<Option<String>>();
+            match chars {
+                // Empty strings don't lex, so `{}` it is
+                Some(chars) if !elements.is_empty() => format!("\"{chars}\""),
+                _ => format!("{{{}}}", comma_separated(elements, indent)),
+            }
+        }
+    }
+}
+
+/// Escapes `ch` for use inside a literal delimited by `quote`.
+fn escape(ch: char, quote: char) -> String {
+    match ch {
+        '\n' => "\\n".to_string(),
+        '\r' => "\\r".to_string(),
+        '\t' => "\\t".to_string(),
+        '\0' => "\\0".to_string(),
+        '\\' => "\\\\".to_string(),
+        ch if ch == quote => format!("\\{ch}"),
+        ch if ch.is_control() => format!("\\u{{{:X}}}", ch as u32),
+        ch => ch.to_string(),
+    }
+}
+
+pub fn symbol(op: &Op) -> &'static str {
+    match op {
+        Op::Add => "+",
+        Op::Sub => "-",
+        Op::Mul => "*",
+        Op::Div => "/",
+        Op::Rem => "%",
+        Op::WrappingAdd => "+%",
+        Op::WrappingSub => "-%",
+        Op::WrappingMul => "*%",
+        Op::BitAnd => "&",
+        Op::BitOr => "|",
+        Op::BitXor => "^",
+        Op::Shl => "<<",
+        Op::Shr => ">>",
+        Op::Ge => ">=",
+        Op::Le => "<=",
+        Op::Gt => ">",
+        Op::Lt => "<",
+        Op::Eq => "=",
+        Op::Neq => "!=",
+    }
+}
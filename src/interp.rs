@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    compiler::{
+        eval::{operation, truncate},
+        runtime::to_char,
+        types::{get_type, TypedExpr, TypedFunc, TypedValue},
+    },
+    parser::Location,
+};
+
+/// A value at runtime. Integers, chars and bools are all `Int`, held the way
+/// the compiled code would after widening them to 64 bits: sign extended for
+/// signed types and zero extended for everything else.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Array(Rc<Vec<Value>>),
+}
+
+impl Value {
+    fn int(&self) -> i64 {
+        match self {
+            Value::Int(x) => *x,
+            Value::Array(_) => unreachable!("The type checker let an array through as an integer"),
+        }
+    }
+
+    fn array(&self) -> &[Value] {
+        match self {
+            Value::Array(elements) => elements,
+            Value::Int(_) => unreachable!("The type checker let an integer through as an array"),
+        }
+    }
+}
+
+/// Why a program stopped before `main` returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    /// A runtime panic, worded like the ones from `core.c`.
+    Panic(String),
+    /// Something only compiled programs can do, like calling C. Indexing past
+    /// the end of an array is one too: compiled code doesn't check bounds and
+    /// reads whatever memory follows.
+    Unsupported(String),
+}
+
+/// Evaluates typed functions directly, with the builtins implemented in Rust
+/// on top of `input` and `output`. It is meant to behave exactly like the
+/// compiled program, so the two can be checked against each other.
+pub struct Interpreter<'a> {
+    functions: HashMap<&'a str, &'a TypedFunc>,
+    checked_arithmetic: bool,
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    /// The file of the function being run, for panic messages.
+    file: &'a str,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(
+        funcs: &'a [TypedFunc],
+        checked_arithmetic: bool,
+        input: &'a mut dyn BufRead,
+        output: &'a mut dyn Write,
+    ) -> Self {
+        Self {
+            functions: funcs
+                .iter()
+                .map(|func| (func.name.as_str(), func))
+                .collect(),
+            checked_arithmetic,
+            input,
+            output,
+            file: "",
+        }
+    }
+
+    /// Runs `main` and returns what it returns.
+    pub fn run(&mut self) -> Result<i64, Stop> {
+        let value = self.call("main", Vec::new());
+        // Like the runtime, get everything printed out before any panic
+        self.output
+            .flush()
+            .map_err(|error| Stop::Unsupported(error.to_string()))?;
+        Ok(value?.int())
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Stop> {
+        let Some(func) = self.functions.get(name).copied() else {
+            return self.call_builtin(name, args);
+        };
+        let mut variables = func
+            .args
+            .iter()
+            .map(|arg| arg.name.clone())
+            .zip(args)
+            .collect::<HashMap<_, _>>();
+        let caller = std::mem::replace(&mut self.file, &func.file);
+        let value = self.eval(&func.body, &mut variables);
+        self.file = caller;
+        value
+    }
+
+    fn call_builtin(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Stop> {
+        let arg = args.first().map(Value::int).unwrap_or_default();
+        let written = match name {
+            "printint" => write!(self.output, "{arg}"),
+            "printintln" => writeln!(self.output, "{arg}"),
+            "printchar" => write!(self.output, "{}", to_char(arg)),
+            "printcharln" => writeln!(self.output, "{}", to_char(arg)),
+            "readchar" => return Ok(Value::Int(self.readchar())),
+            _ => {
+                return Err(Stop::Unsupported(format!(
+                    "{name} is not a builtin, the interpreter can't call C functions"
+                )))
+            }
+        };
+        written.map_err(|error| Stop::Unsupported(error.to_string()))?;
+        Ok(Value::Int(arg))
+    }
+
+    /// The next byte of input that isn't whitespace, or -1 at the end.
+    fn readchar(&mut self) -> i64 {
+        loop {
+            let Ok(buffer) = self.input.fill_buf() else {
+                return -1;
+            };
+            let Some(&byte) = buffer.first() else {
+                return -1;
+            };
+            self.input.consume(1);
+            if !byte.is_ascii_whitespace() {
+                return byte as i8 as i64;
+            }
+        }
+    }
+
+    fn eval(
+        &mut self,
+        expr: &TypedExpr,
+        variables: &mut HashMap<String, Value>,
+    ) -> Result<Value, Stop> {
+        Ok(match expr {
+            TypedExpr::Value(_, TypedValue::Number(x)) => Value::Int(*x),
+            TypedExpr::Value(_, TypedValue::Bool(x)) => Value::Int(*x as i64),
+            TypedExpr::Value(_, TypedValue::Char(x)) => Value::Int(*x as i64),
+            TypedExpr::Value(_, TypedValue::Array(elements)) => Value::Array(Rc::new(
+                elements
+                    .iter()
+                    .map(|element| self.eval(element, variables))
+                    .collect::<Result<_, _>>()?,
+            )),
+            TypedExpr::Ident(_, ident) => variables[ident].clone(),
+            TypedExpr::Operation(_, lhs, op, rhs, location) => {
+                let operand_type = get_type(lhs);
+                let lhs = self.eval(lhs, variables)?.int();
+                let rhs = self.eval(rhs, variables)?.int();
+                let result = operation(&operand_type, lhs, op, rhs, self.checked_arithmetic)
+                    .map_err(|message| panic_at(self.file, *location, message))?;
+                Value::Int(result)
+            }
+            TypedExpr::Def { ident, value } => {
+                let value = self.eval(value, variables)?;
+                variables.insert(ident.clone(), value.clone());
+                value
+            }
+            TypedExpr::Then { lhs, rhs } => {
+                self.eval(lhs, variables)?;
+                self.eval(rhs, variables)?
+            }
+            TypedExpr::FunctionCall(_, name, args, _) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, variables))
+                    .collect::<Result<_, _>>()?;
+                self.call(name, args)?
+            }
+            TypedExpr::IfThen {
+                condition,
+                then,
+                other,
+            } => {
+                if self.eval(condition, variables)?.int() != 0 {
+                    self.eval(then, variables)?
+                } else {
+                    self.eval(other, variables)?
+                }
+            }
+            TypedExpr::Index { target, index, .. } => {
+                let target = self.eval(target, variables)?;
+                let index = self.eval(index, variables)?.int();
+                let elements = target.array();
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|index| elements.get(index))
+                    .cloned()
+                    .ok_or_else(|| {
+                        Stop::Unsupported(format!(
+                            "Index {index} is out of bounds for an array of length {}",
+                            elements.len()
+                        ))
+                    })?
+            }
+            TypedExpr::Each {
+                body,
+                ident,
+                target,
+            } => {
+                let max = self.eval(target, variables)?.int();
+                let mut i = 1;
+                while i <= max {
+                    variables.insert(ident.clone(), Value::Int(i));
+                    self.eval(body, variables)?;
+                    // Like compiled loops, count on from wherever the body
+                    // left the loop variable
+                    i = variables[ident].int().wrapping_add(1);
+                }
+                Value::Int(0)
+            }
+            TypedExpr::Len(array) => Value::Int(self.eval(array, variables)?.array().len() as i64),
+            TypedExpr::Cast { value, to } => {
+                Value::Int(truncate(to, self.eval(value, variables)?.int()))
+            }
+        })
+    }
+}
+
+fn panic_at(file: &str, location: Location, message: &str) -> Stop {
+    Stop::Panic(format!(
+        "panic at {file}:{}:{}: {message}",
+        location.line, location.column
+    ))
+}
@@ -0,0 +1,101 @@
+use std::{
+    env::{self, consts::EXE_SUFFIX},
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command, ExitStatus},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tracing::info_span;
+
+/// The runtime every program is linked against, baked into moistc so users
+/// don't need a copy of core.c next to their code.
+const RUNTIME: &str = include_str!("../core.c");
+
+/// The external programs used to turn an object file into an executable.
+pub struct Toolchain {
+    /// C compiler used to build the runtime.
+    pub cc: String,
+    /// Program used to link, called like a C compiler driver.
+    pub linker: String,
+}
+
+/// Compiles the runtime and links it with `object` into an executable at
+/// `output`. Intermediate files live in a temporary directory that is removed
+/// afterwards.
+pub fn link_executable(object: &[u8], output: &Path, toolchain: &Toolchain) -> Result<(), String> {
+    let _span = info_span!("link").entered();
+    let dir = temp_dir()?;
+    let result = link_in(&dir, object, output, toolchain);
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Links `object` into a temporary executable and runs it with `args`,
+/// sharing moistc's stdin, stdout and stderr. The executable is removed once
+/// it exits. A .wet `main` takes no arguments, so `args` only reach C code.
+pub fn run_executable(
+    object: &[u8],
+    toolchain: &Toolchain,
+    args: &[String],
+) -> Result<ExitStatus, String> {
+    let dir = temp_dir()?;
+    let executable = dir.join(format!("main{EXE_SUFFIX}"));
+    let result = link_executable(object, &executable, toolchain).and_then(|()| {
+        Command::new(&executable)
+            .args(args)
+            .status()
+            .map_err(|error| format!("Failed to run {:?}: {}", executable, error))
+    });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Creates a fresh directory for intermediate files, unique even between
+/// threads of the same process.
+fn temp_dir() -> Result<PathBuf, String> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "moistc-{}-{}",
+        process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).map_err(|error| format!("Failed to create {:?}: {}", dir, error))?;
+    Ok(dir)
+}
+
+fn link_in(dir: &Path, object: &[u8], output: &Path, toolchain: &Toolchain) -> Result<(), String> {
+    let program = dir.join("main.o");
+    let runtime_source = dir.join("core.c");
+    let runtime = dir.join("core.o");
+    fs::write(&program, object)
+        .map_err(|error| format!("Failed to write {:?}: {}", program, error))?;
+    fs::write(&runtime_source, RUNTIME)
+        .map_err(|error| format!("Failed to write {:?}: {}", runtime_source, error))?;
+
+    run(Command::new(&toolchain.cc)
+        .arg("-c")
+        .arg(&runtime_source)
+        .arg("-o")
+        .arg(&runtime))?;
+    run(Command::new(&toolchain.linker)
+        .arg(&program)
+        .arg(&runtime)
+        .arg("-o")
+        .arg(output))
+}
+
+fn run(command: &mut Command) -> Result<(), String> {
+    let status = command
+        .status()
+        .map_err(|error| format!("Failed to run {:?}: {}", command.get_program(), error))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{:?} failed with {}",
+            command.get_program(),
+            status
+        ))
+    }
+}
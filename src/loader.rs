@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use tracing::info_span;
+
+use crate::parser::{parse_program, Expr, Location, Program, Value};
+
+/// What a module offers the modules that use it.
+#[derive(Debug, Clone)]
+struct Exports {
+    /// Put in front of its function names, like `lib::math`.
+    prefix: String,
+    /// Its `pub funion`s.
+    public: HashSet<String>,
+}
+
+/// Reads `file` and every module it uses, directly or not, into one program.
+/// A module's functions are renamed to `path::to::module::name`, after its
+/// path relative to `file`, so modules can use the same names. The
+/// functions of `file` itself keep their names.
+pub fn load(file: &str) -> Result<Program, String> {
+    let root = Path::new(file);
+    let mut loader = Loader {
+        root_dir: root.parent().unwrap_or(Path::new("")).to_path_buf(),
+        loaded: HashMap::new(),
+        stack: Vec::new(),
+        program: Program::default(),
+    };
+    let source =
+        fs::read_to_string(root).map_err(|error| format!("Failed to read {file}: {error}"))?;
+    let key = fs::canonicalize(root).map_err(|error| format!("Failed to read {file}: {error}"))?;
+    loader.module(root, key, source, None)?;
+    Ok(loader.program)
+}
+
+/// Parses `source`, marking its functions as coming from `file`.
+pub fn parse_file(file: &str, source: String) -> Result<Program, String> {
+    let _span = info_span!("parse", file).entered();
+    let mut program = parse_program(source).map_err(|error| format!("{file}:{error}"))?;
+    for func in &mut program.funcs {
+        func.file = file.to_string();
+    }
+    Ok(program)
+}
+
+struct Loader {
+    /// Where the file being compiled is, which module paths start from.
+    root_dir: PathBuf,
+    /// Modules that are done, by canonical path so a module reached two ways
+    /// is still loaded once.
+    loaded: HashMap<PathBuf, Exports>,
+    /// The chain of modules being loaded, by canonical path and the path
+    /// they were named by, to catch modules that end up using themselves.
+    stack: Vec<(PathBuf, String)>,
+    program: Program,
+}
+
+impl Loader {
+    /// Loads the module at `path` after the modules it uses, so every
+    /// function comes after the ones it can call. `prefix` is `None` for the
+    /// file being compiled.
+    fn module(
+        &mut self,
+        path: &Path,
+        key: PathBuf,
+        source: String,
+        prefix: Option<String>,
+    ) -> Result<Exports, String> {
+        let file = path.display().to_string();
+        let mut program = parse_file(&file, source)?;
+
+        self.stack.push((key.clone(), file.clone()));
+        let mut imports = HashMap::new();
+        for module in &program.uses {
+            let error = |message: String| {
+                let Location { line, column } = module.location;
+                format!("{file}:{line}:{column}: {message}")
+            };
+            let mut used = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            used.extend(&module.path);
+            used.set_extension("wet");
+            let unreadable = |read_error: io::Error| {
+                error(format!(
+                    "Failed to read module {}: {read_error}",
+                    used.display()
+                ))
+            };
+            let used_key = fs::canonicalize(&used).map_err(unreadable)?;
+
+            if let Some(start) = self.stack.iter().position(|(key, _)| *key == used_key) {
+                let cycle = self.stack[start..]
+                    .iter()
+                    .map(|(_, name)| name.as_str())
+                    .chain([self.stack[start].1.as_str()])
+                    .collect::<Vec<_>>();
+                return Err(error(format!("Import cycle: {}", cycle.join(" -> "))));
+            }
+            if imports.contains_key(module.alias()) {
+                return Err(error(format!("{} is used twice", module.alias())));
+            }
+            let exports = match self.loaded.get(&used_key) {
+                Some(exports) => exports.clone(),
+                None => {
+                    let source = fs::read_to_string(&used).map_err(unreadable)?;
+                    let prefix = self.prefix(&used);
+                    self.module(&used, used_key, source, Some(prefix))?
+                }
+            };
+            imports.insert(module.alias().to_string(), exports);
+        }
+        self.stack.pop();
+
+        let qualify = |name: &str| match &prefix {
+            Some(prefix) => format!("{prefix}::{name}"),
+            None => name.to_string(),
+        };
+        let own = program
+            .funcs
+            .iter()
+            .map(|func| func.name.clone())
+            .collect::<HashSet<_>>();
+        let resolve = |name: &str, location: Location| {
+            let error = |message: String| {
+                let Location { line, column } = location;
+                format!("{file}:{line}:{column}: {message}")
+            };
+            let Some((alias, name)) = name.split_once("::") else {
+                // Anything else is up to the type checker, like the prelude
+                return Ok(own.contains(name).then(|| qualify(name)));
+            };
+            let module = imports
+                .get(alias)
+                .ok_or_else(|| error(format!("{alias} is not used, add `use {alias}`")))?;
+            if !module.public.contains(name) {
+                return Err(error(format!("{alias} has no pub funion {name}")));
+            }
+            Ok(Some(format!("{}::{name}", module.prefix)))
+        };
+        for func in &mut program.funcs {
+            resolve_calls(&mut func.body, &resolve)?;
+        }
+
+        let exports = Exports {
+            prefix: prefix.clone().unwrap_or_default(),
+            public: program
+                .funcs
+                .iter()
+                .filter(|func| func.public)
+                .map(|func| func.name.clone())
+                .collect(),
+        };
+        for func in &mut program.funcs {
+            func.name = qualify(&func.name);
+        }
+        program.uses.clear();
+        self.loaded.insert(key, exports.clone());
+        self.program.extend(program);
+        Ok(exports)
+    }
+
+    /// `lib::math` for `lib/math.wet` next to the file being compiled.
+    fn prefix(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.root_dir).unwrap_or(path);
+        relative
+            .with_extension("")
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("::")
+    }
+}
+
+/// Renames every function called in `expr` to what `resolve` returns for it,
+/// if anything.
+fn resolve_calls(
+    expr: &mut Expr,
+    resolve: &impl Fn(&str, Location) -> Result<Option<String>, String>,
+) -> Result<(), String> {
+    match expr {
+        Expr::FunctionCall(name, args, location) => {
+            if let Some(resolved) = resolve(name, *location)? {
+                *name = resolved;
+            }
+            for arg in args {
+                resolve_calls(arg, resolve)?;
+            }
+        }
+        Expr::Value(Value::Array(elements)) => {
+            for element in elements {
+                resolve_calls(element, resolve)?;
+            }
+        }
+        Expr::Value(_) | Expr::Ident(_) => {}
+        Expr::Operation(lhs, _, rhs, _)
+        | Expr::Then { lhs, rhs }
+        | Expr::Index {
+            target: lhs,
+            index: rhs,
+        }
+        | Expr::Each {
+            target: lhs,
+            body: rhs,
+            ..
+        } => {
+            resolve_calls(lhs, resolve)?;
+            resolve_calls(rhs, resolve)?;
+        }
+        Expr::IfThen {
+            condition,
+            then,
+            other,
+        } => {
+            resolve_calls(condition, resolve)?;
+            resolve_calls(then, resolve)?;
+            resolve_calls(other, resolve)?;
+        }
+        Expr::Def { value, .. } | Expr::Len(value) | Expr::Cast { value, .. } => {
+            resolve_calls(value, resolve)?;
+        }
+    }
+    Ok(())
+}
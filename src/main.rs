@@ -1,49 +1,498 @@
-use std::fs::{self, File};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::{self, ExitCode, ExitStatus},
+    str::FromStr,
+};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use cranelift_jit::JITModule;
+use cranelift_object::ObjectModule;
 use lalrpop_util::lalrpop_mod;
-use target_lexicon::Triple;
+use target_lexicon::{OperatingSystem, Triple};
+use tracing::info_span;
 
-use crate::compiler::types::TypeGenerator;
+use crate::{
+    compiler::{fold, types::TypeGenerator, Artifacts, Compiler, CompilerOptions, OptLevel},
+    interp::{Interpreter, Stop},
+    link::Toolchain,
+    parser::Program,
+};
 
 mod compiler;
+mod fmt;
+mod interp;
+mod link;
+mod loader;
 mod parser;
+mod repl;
+mod trace;
 
 #[cfg(test)]
 mod test;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
-    /// Number of times to greet
-    mode: String,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 
-    /// File to work on
-    file: String,
+    /// Print how long each stage and each function took to stderr
+    #[arg(long, global = true)]
+    time_passes: bool,
 }
 
-lalrpop_mod!(grammar);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Parse a file and print its syntax tree
+    Parse {
+        /// File to parse
+        file: String,
+    },
+    /// Type check a file and report operations on constants that always
+    /// panic, without compiling it
+    Check {
+        /// File to check
+        file: String,
 
-fn main() {
-    let args = Args::parse();
-    let prelude = include_str!("../prelude.wet");
-    let file = fs::read_to_string(args.file).expect("Failed to read from file");
-    let file = format!("{prelude}\n{file}");
-    println!("{file}");
-    match &args.mode[..] {
-        "lex" => {
-            dbg!(parser::extract_funcs(file));
+        /// Also report constant arithmetic that overflows, as `build --checked`
+        /// would
+        #[arg(long)]
+        checked: bool,
+    },
+    /// Compile a file to an executable or another artifact
+    Build {
+        /// File to compile
+        file: String,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+
+        /// What to produce
+        #[arg(long, value_enum, default_value_t = Emit::Exe)]
+        emit: Emit,
+
+        /// Target triple to compile for, such as aarch64-unknown-linux-gnu
+        #[arg(long, value_parser = parse_target, default_value_t = Triple::host())]
+        target: Triple,
+
+        /// Include debug info, so debuggers can step through the source
+        #[arg(short = 'g')]
+        debug_info: bool,
+
+        #[command(flatten)]
+        toolchain: ToolchainArgs,
+
+        /// Where to write the output, named after the input file by default
+        #[arg(short, long, conflicts_with = "out_dir")]
+        output: Option<PathBuf>,
+
+        /// Directory to write the default-named output into
+        #[arg(long)]
+        out_dir: Option<PathBuf>,
+
+        /// Also write the tokens, syntax trees and IRs into this directory
+        #[arg(long, value_name = "DIR")]
+        dump_to: Option<PathBuf>,
+    },
+    /// Compile a file and run it
+    Run {
+        /// File to run
+        file: String,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+
+        /// Include debug info, so debuggers can step through the source
+        #[arg(short = 'g')]
+        debug_info: bool,
+
+        #[command(flatten)]
+        toolchain: ToolchainArgs,
+
+        /// Arguments passed on to the program, after `--`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Compile a file in memory and run it without a C compiler
+    Jit {
+        /// File to run
+        file: String,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+    },
+    /// Run a file with the interpreter instead of compiling it
+    Interp {
+        /// File to run
+        file: String,
+
+        #[command(flatten)]
+        codegen: CodegenArgs,
+    },
+    /// Type in definitions and expressions and see their values
+    Repl {
+        #[command(flatten)]
+        codegen: CodegenArgs,
+    },
+    /// Rewrite a file in the canonical style
+    Fmt {
+        /// File to format
+        file: String,
+
+        /// Don't write anything, fail if the file isn't formatted
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+struct CodegenArgs {
+    /// Panic on integer overflow instead of wrapping around
+    #[arg(long)]
+    checked: bool,
+
+    /// Optimization level
+    #[arg(short = 'O', value_enum, default_value_t = Opt::O0)]
+    opt: Opt,
+
+    /// Set a Cranelift setting, can be repeated
+    #[arg(long = "cranelift-flag", value_name = "KEY=VALUE", value_parser = parse_flag)]
+    cranelift_flags: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Opt {
+    /// No optimizations
+    #[value(name = "0")]
+    O0,
+    /// Optimize for speed
+    #[value(name = "1")]
+    O1,
+    /// Optimize for speed, same as -O1
+    #[value(name = "2")]
+    O2,
+    /// Optimize for speed and size
+    #[value(name = "s")]
+    Os,
+}
+
+#[derive(Args, Debug)]
+struct ToolchainArgs {
+    /// C compiler used to build the runtime
+    #[arg(long, default_value = "cc")]
+    cc: String,
+
+    /// Program used to link the executable
+    #[arg(long, default_value = "cc")]
+    linker: String,
+}
+
+impl CodegenArgs {
+    fn options(&self, disassemble: bool) -> CompilerOptions {
+        CompilerOptions {
+            checked_arithmetic: self.checked,
+            disassemble,
+            opt_level: match self.opt {
+                Opt::O0 => OptLevel::None,
+                Opt::O1 | Opt::O2 => OptLevel::Speed,
+                Opt::Os => OptLevel::SpeedAndSize,
+            },
+            cranelift_flags: self.cranelift_flags.clone(),
+            debug_info: false,
         }
-        "type" => {
-            let funcs = parser::extract_funcs(file);
-            let mut type_generator = TypeGenerator::new();
-            dbg!(type_generator.generate_types(funcs));
+    }
+}
+
+impl From<ToolchainArgs> for Toolchain {
+    fn from(args: ToolchainArgs) -> Self {
+        Toolchain {
+            cc: args.cc,
+            linker: args.linker,
         }
-        "build" => {
-            let compiler = compiler::Compiler::new();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// An executable linked with the runtime
+    Exe,
+    /// An object file to link yourself
+    Obj,
+    /// Disassembled machine code
+    Asm,
+    /// Cranelift IR
+    Clif,
+    /// The mid-level IR, after optimization
+    Mir,
+    /// The tokens the parser sees, one per line
+    Tokens,
+    /// The syntax tree, printed as source
+    Ast,
+    /// The syntax tree after type checking, with types written out
+    TypedAst,
+}
 
-            compiler.build(TypeGenerator::new().generate_types(parser::extract_funcs(file)));
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    grammar
+);
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let timer = trace::init(cli.time_passes);
+    let code = run(cli.command).unwrap_or_else(|error| {
+        for line in error.lines() {
+            eprintln!("error: {line}");
         }
-        unknown => println!("Unknown compiler command: {}", unknown),
+        ExitCode::FAILURE
+    });
+    if let Some(timer) = timer {
+        eprint!("{}", timer.report());
     }
+    code
+}
+
+fn run(command: Command) -> Result<ExitCode, String> {
+    match command {
+        Command::Parse { file } => {
+            print!("{}", fmt::format_program(&parse(&file, read(&file)?)));
+        }
+        Command::Check { file, checked } => {
+            let typed = TypeGenerator::new().generate_types(load(&file)?);
+            fold::fold_program(typed, checked).map_err(|diagnostics| {
+                diagnostics
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })?;
+        }
+        Command::Build {
+            file,
+            codegen,
+            emit,
+            target,
+            debug_info,
+            toolchain,
+            output,
+            out_dir,
+            dump_to,
+        } => {
+            let options = CompilerOptions {
+                debug_info,
+                ..codegen.options(emit == Emit::Asm || dump_to.is_some())
+            };
+            for dir in out_dir.iter().chain(&dump_to) {
+                fs::create_dir_all(dir)
+                    .map_err(|error| format!("Failed to create {}: {error}", dir.display()))?;
+            }
+            let path = output_path(&file, emit, &target, output.as_deref(), out_dir.as_deref());
+            let artifacts = compile(&file, options, &target, emit, |stage, contents| {
+                if let Some(dir) = &dump_to {
+                    write(
+                        &output_path(&file, stage, &target, None, Some(dir)),
+                        contents,
+                    )?;
+                }
+                if stage == emit {
+                    write(&path, contents)?;
+                }
+                Ok(())
+            })?;
+            match (emit, artifacts) {
+                (Emit::Exe, Some(artifacts)) => {
+                    link::link_executable(&artifacts.object, &path, &toolchain.into())?
+                }
+                (Emit::Obj, Some(artifacts)) => write(&path, &artifacts.object)?,
+                _ => {}
+            }
+        }
+        Command::Run {
+            file,
+            codegen,
+            debug_info,
+            toolchain,
+            args,
+        } => {
+            let options = CompilerOptions {
+                debug_info,
+                ..codegen.options(false)
+            };
+            let artifacts = compile(&file, options, &Triple::host(), Emit::Exe, |_, _| Ok(()))?
+                .expect("executables go through every stage");
+            let status = link::run_executable(&artifacts.object, &toolchain.into(), &args)?;
+            return Ok(exit_code(status));
+        }
+        Command::Jit { file, codegen } => {
+            let program = TypeGenerator::new().generate_types(load(&file)?);
+            let value = Compiler::<JITModule>::new(codegen.options(false))?.run(program)?;
+            // Like a C main returning an int, only the low byte survives
+            return Ok(ExitCode::from(value as u8));
+        }
+        Command::Interp { file, codegen } => {
+            let program = TypeGenerator::new().generate_types(load(&file)?);
+            let mut stdin = io::stdin().lock();
+            let mut stdout = io::stdout();
+            let mut interpreter =
+                Interpreter::new(&program.funcs, codegen.checked, &mut stdin, &mut stdout);
+            return match interpreter.run() {
+                Ok(value) => Ok(ExitCode::from(value as u8)),
+                // Stop the same way a compiled program would
+                Err(Stop::Panic(message)) => {
+                    eprintln!("{message}");
+                    Ok(ExitCode::from(101))
+                }
+                Err(Stop::Unsupported(message)) => Err(message),
+            };
+        }
+        Command::Repl { codegen } => repl::repl(prelude(), codegen.options(false))?,
+        Command::Fmt { file, check } => {
+            let source = read(&file)?;
+            let formatted = fmt::format_program(&parse(&file, source.clone()));
+            if formatted != source {
+                if check {
+                    return Err(format!("{file} is not formatted"));
+                }
+                write(Path::new(&file), formatted)?;
+            }
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The exit code to leave with after running a program, mirroring the
+/// program's own or the shell convention of 128 + signal if it was killed.
+fn exit_code(status: ExitStatus) -> ExitCode {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return ExitCode::from(128u8.wrapping_add(signal as u8));
+    }
+    ExitCode::from(status.code().unwrap_or(1) as u8)
+}
+
+/// Loads `file` and the modules it uses together with the prelude it is
+/// implicitly compiled with.
+fn load(file: &str) -> Result<Program, String> {
+    let mut program = prelude();
+    program.extend(loader::load(file)?);
+    Ok(program)
+}
+
+/// Where the prelude is in moistc's sources. Its functions are said to come
+/// from there in panics and debug info, since the copy moistc compiles is
+/// embedded into it.
+const PRELUDE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/prelude.wet");
+
+fn prelude() -> Program {
+    parse(
+        PRELUDE,
+        include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/prelude.wet")).to_string(),
+    )
+}
+
+/// Compiles `file` and the prelude into an object file for `target`,
+/// handing every stage to `stage` as soon as it exists so a program that
+/// fails later on can still be dumped. Stops after `last`, returning the
+/// artifacts only if code generation was reached. Only the file's own
+/// tokens and functions end up in the dumps.
+fn compile(
+    file: &str,
+    options: CompilerOptions,
+    target: &Triple,
+    last: Emit,
+    mut stage: impl FnMut(Emit, &[u8]) -> Result<(), String>,
+) -> Result<Option<Artifacts>, String> {
+    let compiler = Compiler::<ObjectModule>::for_target(options, target)?;
+    let source = read(file)?;
+    let tokens: String = info_span!("lex", file)
+        .in_scope(|| parser::tokens(&source))
+        .map_err(|error| format!("{file}:{error}"))?
+        .into_iter()
+        .map(|(location, token)| format!("{}:{}\t{token:?}\n", location.line, location.column))
+        .collect();
+    stage(Emit::Tokens, tokens.as_bytes())?;
+    if last == Emit::Tokens {
+        return Ok(None);
+    }
+
+    let ast = fmt::format_program(&parse(file, source));
+    stage(Emit::Ast, ast.as_bytes())?;
+    if last == Emit::Ast {
+        return Ok(None);
+    }
+
+    let typed = TypeGenerator::new().generate_types(load(file)?);
+    let typed_ast: String = typed
+        .funcs
+        .iter()
+        .filter(|func| func.file == file)
+        .map(fmt::format_typed_func)
+        .collect();
+    stage(Emit::TypedAst, typed_ast.as_bytes())?;
+    if last == Emit::TypedAst {
+        return Ok(None);
+    }
+
+    let artifacts = compiler.build(typed)?;
+    stage(Emit::Mir, artifacts.mir.as_bytes())?;
+    stage(Emit::Clif, artifacts.clif.as_bytes())?;
+    stage(Emit::Asm, artifacts.asm.as_bytes())?;
+    Ok(Some(artifacts))
+}
+
+fn parse_flag(flag: &str) -> Result<(String, String), String> {
+    flag.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {flag}"))
+}
+
+fn parse_target(triple: &str) -> Result<Triple, String> {
+    Triple::from_str(triple).map_err(|error| format!("{error}"))
+}
+
+/// `output` if it was given, otherwise the input's file stem with the
+/// extension for `emit` on `target`, placed in `out_dir` or the current
+/// directory.
+fn output_path(
+    file: &str,
+    emit: Emit,
+    target: &Triple,
+    output: Option<&Path>,
+    out_dir: Option<&Path>,
+) -> PathBuf {
+    if let Some(output) = output {
+        return output.to_path_buf();
+    }
+    let stem = Path::new(file)
+        .file_stem()
+        .map_or("main".into(), |stem| stem.to_string_lossy());
+    let name = match emit {
+        Emit::Exe if target.operating_system == OperatingSystem::Windows => format!("{stem}.exe"),
+        Emit::Exe => stem.to_string(),
+        Emit::Obj => format!("{stem}.o"),
+        Emit::Asm => format!("{stem}.s"),
+        Emit::Clif => format!("{stem}.clif"),
+        Emit::Mir => format!("{stem}.mir"),
+        Emit::Tokens => format!("{stem}.tokens"),
+        Emit::Ast => format!("{stem}.ast"),
+        Emit::TypedAst => format!("{stem}.typed-ast"),
+    };
+    out_dir.unwrap_or(Path::new("")).join(name)
+}
+
+fn read(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|error| format!("Failed to read {path}: {error}"))
+}
+
+fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), String> {
+    fs::write(path, contents)
+        .map_err(|error| format!("Failed to write {}: {error}", path.display()))
+}
+
+fn parse(name: &str, source: String) -> Program {
+    loader::parse_file(name, source).unwrap_or_else(|error| {
+        eprintln!("{error}");
+        process::exit(1);
+    })
 }
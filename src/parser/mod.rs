@@ -7,6 +7,10 @@
 // }
 // pub mod ast;
 
+use std::fmt::{self, Display};
+
+use lalrpop_util::{lexer::Token, ParseError};
+
 use crate::{compiler::types::Type, grammar};
 
 // #[derive(Logos, Debug, PartialEq)]
@@ -42,8 +46,10 @@ use crate::{compiler::types::Type, grammar};
 //
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
-    Number(i32),
+    /// The value and the literal as written, so formatting keeps its base
+    Number(i64, String),
     Bool(bool),
+    Char(char),
     Array(Vec<Expr>),
 }
 
@@ -51,7 +57,7 @@ pub enum Value {
 pub enum Expr {
     Value(Value),
     Ident(String),
-    Operation(Box<Expr>, Op, Box<Expr>),
+    Operation(Box<Expr>, Op, Box<Expr>, Location),
     Def {
         ident: String,
         value: Box<Expr>,
@@ -60,7 +66,7 @@ pub enum Expr {
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
-    FunctionCall(String, Vec<Box<Expr>>),
+    FunctionCall(String, Vec<Expr>, Location),
     IfThen {
         condition: Box<Expr>,
         then: Box<Expr>,
@@ -76,13 +82,67 @@ pub enum Expr {
         target: Box<Expr>,
     },
     Len(Box<Expr>),
+    Cast {
+        value: Box<Expr>,
+        to: Type,
+    },
 }
 
 #[derive(Debug, Clone)]
 pub struct Func {
     pub name: String,
     pub args: Vec<Arg>,
+    /// The declared return type. Functions have to declare it to be called
+    /// before they are done being checked, e.g. recursively.
+    pub ret: Option<Type>,
     pub body: Expr,
+    /// Declared with `pub funion`, so modules using this one can call it.
+    pub public: bool,
+    /// Where `funion` is.
+    pub location: Location,
+    /// The path of the file the function is in, empty if it wasn't read
+    /// from a file.
+    pub file: String,
+}
+
+/// A function implemented outside of moistc, e.g. in C, declared with
+/// `extern funion name[args] -> Type`.
+#[derive(Debug, Clone)]
+pub struct ExternFunc {
+    pub name: String,
+    pub args: Vec<Arg>,
+    /// `None` when the function returns nothing (`void` in C).
+    pub ret: Option<Type>,
+}
+
+/// A `use path/to/module` declaration, naming a file relative to the one
+/// it is in without the `.wet`.
+#[derive(Debug, Clone)]
+pub struct Use {
+    pub path: Vec<String>,
+    pub location: Location,
+}
+
+impl Use {
+    /// The name the module's functions are called by, `module::name`.
+    pub fn alias(&self) -> &str {
+        self.path.last().expect("use has a path")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub funcs: Vec<Func>,
+    pub externs: Vec<ExternFunc>,
+    pub uses: Vec<Use>,
+}
+
+impl Program {
+    pub fn extend(&mut self, other: Program) {
+        self.funcs.extend(other.funcs);
+        self.externs.extend(other.externs);
+        self.uses.extend(other.uses);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -97,6 +157,15 @@ pub enum Op {
     Sub,
     Mul,
     Div,
+    Rem,
+    WrappingAdd,
+    WrappingSub,
+    WrappingMul,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
     Ge,
     Le,
     Gt,
@@ -105,8 +174,114 @@ pub enum Op {
     Neq,
 }
 
-pub fn extract_funcs(string: String) -> Vec<Func> {
-    grammar::FunctionsParser::new().parse(&string).unwrap()
+/// Resolves the escape sequences in the body of a char or string literal.
+/// Supports `\n`, `\r`, `\t`, `\0`, `\\`, `\'`, `\"` and `\u{...}`.
+pub fn unescape(literal: &str) -> Result<Vec<char>, &'static str> {
+    let mut chars = literal.chars();
+    let mut unescaped = Vec::new();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        unescaped.push(match chars.next().ok_or("Unterminated escape sequence")? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            'u' => {
+                if chars.next() != Some('{') {
+                    return Err("Expected { after \\u");
+                }
+                let digits = chars
+                    .by_ref()
+                    .take_while(|ch| *ch != '}')
+                    .collect::<String>();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or("Invalid unicode escape")?
+            }
+            _ => return Err("Unknown escape sequence"),
+        });
+    }
+    Ok(unescaped)
+}
+
+/// Parses the digits of an integer literal in the given radix, ignoring `_`
+/// separators.
+pub fn parse_int(digits: &str, radix: u32) -> Result<i64, &'static str> {
+    let digits = digits.replace('_', "");
+    if digits.is_empty() {
+        return Err("Integer literal has no digits");
+    }
+    i64::from_str_radix(&digits, radix).map_err(|_| "Integer literal does not fit in 64 bits")
+}
+
+/// A line and column in a source file, both starting at 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Where each line of a source starts, for finding the [`Location`] of an
+/// offset without rescanning everything before it.
+pub struct Lines<'a> {
+    source: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+    pub fn new(source: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { source, starts }
+    }
+
+    pub fn location(&self, offset: usize) -> Location {
+        let offset = offset.min(self.source.len());
+        let line = self.starts.partition_point(|start| *start <= offset);
+        Location {
+            line,
+            column: self.source[self.starts[line - 1]..offset].chars().count() + 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl SyntaxError {
+    fn new(lines: &Lines, offset: usize, message: String) -> Self {
+        let Location { line, column } = lines.location(offset);
+        Self {
+            line,
+            column,
+            message,
+        }
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+pub fn parse_program(string: String) -> Result<Program, SyntaxError> {
+    let lines = Lines::new(&string);
+    grammar::ProgramParser::new()
+        .parse(&lines, &string)
+        .map_err(|error| syntax_error(&lines, error))
     // let lexer = Token::lexer(&string);
     // lexer
     //     .enumerate()
@@ -117,3 +292,63 @@ pub fn extract_funcs(string: String) -> Vec<Func> {
     //     })
     //     .collect::<Vec<Token>>()
 }
+
+/// Parses a lone expression, as typed into the REPL.
+pub fn parse_expression(string: String) -> Result<Expr, SyntaxError> {
+    let lines = Lines::new(&string);
+    grammar::ExpressionParser::new()
+        .parse(&lines, &string)
+        .map_err(|error| syntax_error(&lines, error))
+}
+
+/// Splits `string` into the tokens the parser sees, each with where it
+/// starts.
+pub fn tokens(string: &str) -> Result<Vec<(Location, &str)>, SyntaxError> {
+    let lines = Lines::new(string);
+    grammar::TokensParser::new()
+        .parse(&lines, string)
+        .map(|tokens| {
+            tokens
+                .into_iter()
+                .map(|(offset, token)| (lines.location(offset), token))
+                .collect()
+        })
+        .map_err(|error| syntax_error(&lines, error))
+}
+
+fn syntax_error(
+    lines: &Lines,
+    error: ParseError<usize, Token<'_>, (usize, &'static str)>,
+) -> SyntaxError {
+    match error {
+        ParseError::InvalidToken { location } => {
+            SyntaxError::new(lines, location, "Invalid token".to_string())
+        }
+        ParseError::UnrecognizedEof { location, expected } => SyntaxError::new(
+            lines,
+            location,
+            format!(
+                "Unexpected end of file, expected one of {}",
+                expected.join(", ")
+            ),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (location, token, _),
+            expected,
+        } => SyntaxError::new(
+            lines,
+            location,
+            format!(
+                "Unexpected {}, expected one of {}",
+                token.1,
+                expected.join(", ")
+            ),
+        ),
+        ParseError::ExtraToken {
+            token: (location, token, _),
+        } => SyntaxError::new(lines, location, format!("Unexpected {}", token.1)),
+        ParseError::User {
+            error: (location, message),
+        } => SyntaxError::new(lines, location, message.to_string()),
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+};
+
+use cranelift_jit::JITModule;
+
+use crate::{
+    compiler::{
+        builtins::builtins,
+        types::{Type, TypeGenerator},
+        Compiler, CompilerOptions,
+    },
+    parser::{parse_expression, parse_program, Func, Location, Program},
+};
+
+/// Reads `funion` definitions and expressions from stdin until it ends. Each
+/// expression becomes a function of its own that is JIT compiled and called
+/// straight away, and its value is printed along with its type.
+pub fn repl(prelude: Program, options: CompilerOptions) -> Result<(), String> {
+    // Type errors are panics, so print them like any other error and carry on
+    panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown error");
+        eprintln!("error: {message}");
+    }));
+
+    let mut session = Session::new(prelude, options)?;
+
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        let mut input = String::new();
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { "... " });
+            io::stdout().flush().map_err(|error| error.to_string())?;
+            match lines.next() {
+                Some(line) => input += &(line.map_err(|error| error.to_string())? + "\n"),
+                None => return Ok(()),
+            }
+            if !is_incomplete(&input) {
+                break;
+            }
+        }
+        if input.trim().is_empty() {
+            continue;
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| session.evaluate(&input))) {
+            Ok(Ok(Some(value))) => println!("{value}"),
+            Ok(Ok(None)) | Err(_) => {}
+            Ok(Err(error)) => {
+                for line in error.lines() {
+                    eprintln!("error: {line}");
+                }
+            }
+        }
+    }
+}
+
+pub struct Session {
+    type_generator: TypeGenerator,
+    compiler: Compiler<JITModule>,
+    /// Every function name taken so far, since the JIT can't redefine them.
+    defined: HashSet<String>,
+    expressions: usize,
+}
+
+impl Session {
+    /// A session that knows the builtins and the functions of `prelude`.
+    pub fn new(prelude: Program, options: CompilerOptions) -> Result<Self, String> {
+        let mut session = Session {
+            type_generator: TypeGenerator::new(),
+            compiler: Compiler::new(options)?,
+            defined: builtins().into_iter().map(|func| func.name).collect(),
+            expressions: 0,
+        };
+        session.define(prelude)?;
+        Ok(session)
+    }
+
+    /// Runs one complete input, returning the value of an expression.
+    pub fn evaluate(&mut self, input: &str) -> Result<Option<String>, String> {
+        // Only whole keywords, so calls like `useful[]` are still expressions
+        let first_word = input
+            .trim_start()
+            .split(|ch: char| !(ch.is_ascii_alphabetic() || ch == '_'))
+            .next()
+            .unwrap_or_default();
+        if ["funion", "pub", "extern", "use"].contains(&first_word) {
+            let program = parse_program(input.to_string()).map_err(|error| error.to_string())?;
+            self.define(program)?;
+            return Ok(None);
+        }
+
+        let body = parse_expression(input.to_string()).map_err(|error| error.to_string())?;
+        self.expressions += 1;
+        // Not a valid identifier, so it can't clash with a user's function
+        let name = format!("expr{}", self.expressions);
+        let program = self.type_generator.generate_types(Program {
+            funcs: vec![Func {
+                name: name.clone(),
+                args: Vec::new(),
+                ret: None,
+                body,
+                public: false,
+                location: Location { line: 1, column: 1 },
+                file: String::new(),
+            }],
+            ..Program::default()
+        });
+        let ret = program.funcs[0].func_type.ret.clone();
+        self.compiler.export(&name);
+        self.compiler.define(program)?;
+        let value = self.compiler.call(&name, &ret);
+        Ok(Some(format!("{}: {ret}", format_value(value, &ret))))
+    }
+
+    fn define(&mut self, program: Program) -> Result<(), String> {
+        if !program.uses.is_empty() {
+            return Err("use only works in files, not in the repl".to_string());
+        }
+        for func in &program.funcs {
+            if self.defined.contains(&func.name) {
+                return Err(format!("{} is already defined", func.name));
+            }
+        }
+        let names = program
+            .funcs
+            .iter()
+            .map(|func| func.name.clone())
+            .collect::<Vec<_>>();
+        let program = self.type_generator.generate_types(program);
+        self.compiler.define(program)?;
+        self.defined.extend(names);
+        Ok(())
+    }
+}
+
+fn format_value(value: i64, value_type: &Type) -> String {
+    match value_type {
+        Type::Bool => (value != 0).to_string(),
+        Type::Char => match u32::try_from(value).ok().and_then(char::from_u32) {
+            Some(ch) => format!("{ch:?}"),
+            None => value.to_string(),
+        },
+        // Arrays may live on the stack of the function that just returned
+        Type::Array(_) => "<array>".to_string(),
+        Type::UInt64 => (value as u64).to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Whether `input` opens more blocks with `(`, `if` and `each` than it closes
+/// with `)` and `end`, so the REPL should keep reading lines.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut code = String::new();
+    let mut chars = input.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '"' && ch != '\'' {
+            code.push(ch);
+            continue;
+        }
+        // Brackets and words inside literals don't count
+        while let Some(next) = chars.next() {
+            if next == '\\' {
+                chars.next();
+            } else if next == ch {
+                break;
+            }
+        }
+        code.push(' ');
+    }
+    let words = code
+        .split(|ch: char| !(ch.is_ascii_alphabetic() || ch == '_'))
+        .collect::<Vec<_>>();
+    let opened = code.matches('(').count()
+        + words
+            .iter()
+            .filter(|word| **word == "if" || **word == "each")
+            .count();
+    let closed = code.matches(')').count() + words.iter().filter(|word| **word == "end").count();
+    opened > closed
+}
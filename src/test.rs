@@ -1,4 +1,18 @@
-use crate::parser::extract_funcs;
+use cranelift_jit::JITModule;
+use cranelift_object::ObjectModule;
+
+use crate::{
+    compiler::{
+        builtins::{builtins, runtime_functions},
+        runtime,
+        types::{Type, TypeGenerator, TypedExpr},
+        Compiler, CompilerOptions, OptLevel,
+    },
+    fmt::{format_program, format_typed_func},
+    interp::{Interpreter, Stop},
+    link::{link_executable, run_executable, Toolchain},
+    parser::{parse_program, tokens, Expr, Location, Op, SyntaxError, Value},
+};
 
 #[test]
 fn test_expr() {
@@ -19,3 +33,1102 @@ fn test_string() {
     //     vec![Token::Str("hello world".to_string()), Token::Period]
     // );
 }
+#[test]
+fn test_string_literal() {
+    let funcs = parse_program(String::from(r#"funion main[] ("hi")"#))
+        .unwrap()
+        .funcs;
+    assert_eq!(
+        funcs[0].body,
+        Expr::Value(Value::Array(vec![
+            Expr::Value(Value::Char('h')),
+            Expr::Value(Value::Char('i')),
+        ]))
+    );
+}
+#[test]
+fn test_char_literals() {
+    let funcs = parse_program(String::from(
+        r#"funion main[] ({'a', ' ', '\n', '\'', '\u{1F4A6}', 'é'})"#,
+    ))
+    .unwrap()
+    .funcs;
+    assert_eq!(
+        funcs[0].body,
+        Expr::Value(Value::Array(vec![
+            Expr::Value(Value::Char('a')),
+            Expr::Value(Value::Char(' ')),
+            Expr::Value(Value::Char('\n')),
+            Expr::Value(Value::Char('\'')),
+            Expr::Value(Value::Char('💦')),
+            Expr::Value(Value::Char('é')),
+        ]))
+    );
+}
+#[test]
+fn test_literals_are_read_only_data() {
+    use cranelift_object::object::{
+        read::elf::ElfFile64, Endianness, Object, ObjectSection, SectionKind,
+    };
+
+    let program = TypeGenerator::new().generate_types(
+        parse_program(
+            r#"funion first[s: Array<Char>] -> Char (s{0})
+funion main[] (printchar[first["hello"]]. printchar[first["hello"]]. printchar[first["world"]];;)"#
+                .to_string(),
+        )
+        .unwrap(),
+    );
+    let object = Compiler::<ObjectModule>::for_target(
+        CompilerOptions::default(),
+        &"x86_64-unknown-linux-gnu".parse().unwrap(),
+    )
+    .unwrap()
+    .build(program)
+    .unwrap()
+    .object;
+
+    // A literal is its length followed by its elements, eight bytes each
+    let encode = |text: &str| {
+        [text.len() as i64]
+            .into_iter()
+            .chain(text.chars().map(|ch| ch as i64))
+            .flat_map(i64::to_le_bytes)
+            .collect::<Vec<_>>()
+    };
+    let elf = ElfFile64::<Endianness>::parse(&*object).unwrap();
+    let count = |kind: SectionKind, text: &str| {
+        let bytes = encode(text);
+        elf.sections()
+            .filter(|section| section.kind() == kind)
+            .map(|section| {
+                section
+                    .data()
+                    .unwrap()
+                    .windows(bytes.len())
+                    .filter(|window| *window == bytes)
+                    .count()
+            })
+            .sum::<usize>()
+    };
+    // Both "hello"s share one object
+    assert_eq!(count(SectionKind::ReadOnlyData, "hello"), 1);
+    assert_eq!(count(SectionKind::ReadOnlyData, "world"), 1);
+    assert_eq!(count(SectionKind::Data, "hello"), 0);
+}
+#[test]
+fn test_string_escapes() {
+    let funcs = parse_program(String::from(
+        r#"funion main[] ("a\"b\\") funion other[] ("c")"#,
+    ))
+    .unwrap()
+    .funcs;
+    assert_eq!(
+        funcs[0].body,
+        Expr::Value(Value::Array(vec![
+            Expr::Value(Value::Char('a')),
+            Expr::Value(Value::Char('"')),
+            Expr::Value(Value::Char('b')),
+            Expr::Value(Value::Char('\\')),
+        ]))
+    );
+    assert_eq!(funcs.len(), 2);
+}
+#[test]
+#[should_panic(expected = "The types of lhs and rhs are not equal")]
+fn test_char_is_not_int() {
+    TypeGenerator::new()
+        .generate_types(parse_program(String::from("funion main[] ('a' + 1)")).unwrap());
+}
+#[test]
+fn test_integer_literals() {
+    let funcs = parse_program(String::from(
+        "funion main[] ({1_000_000, 0xFF, 0o17, 0b1010_1010, 9223372036854775807})",
+    ))
+    .unwrap()
+    .funcs;
+    assert_eq!(
+        funcs[0].body,
+        Expr::Value(Value::Array(vec![
+            Expr::Value(Value::Number(1_000_000, "1_000_000".to_string())),
+            Expr::Value(Value::Number(0xFF, "0xFF".to_string())),
+            Expr::Value(Value::Number(0o17, "0o17".to_string())),
+            Expr::Value(Value::Number(0b1010_1010, "0b1010_1010".to_string())),
+            Expr::Value(Value::Number(i64::MAX, "9223372036854775807".to_string())),
+        ]))
+    );
+}
+#[test]
+fn test_integer_literal_overflow() {
+    let error = parse_program(String::from(
+        "funion main[] (\n  printint[9223372036854775808]\n)",
+    ))
+    .unwrap_err();
+    assert_eq!(
+        error,
+        SyntaxError {
+            line: 2,
+            column: 12,
+            message: "Integer literal does not fit in 64 bits".to_string(),
+        }
+    );
+}
+#[test]
+fn test_casts() {
+    let program = TypeGenerator::new().generate_types(
+        parse_program(String::from(
+            "funion main[x: Int8] (if x as UInt32 >(3 as UInt32) then 'a' as Int16 else x as Int16 end)",
+        ))
+        .unwrap(),
+    );
+    assert_eq!(program.funcs[0].func_type.ret, Type::Int16);
+
+    // Arguments keep the folder away, so these run the generated code
+    let run = |source: &str| {
+        let program =
+            TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+        Compiler::<JITModule>::new(CompilerOptions::default())
+            .unwrap()
+            .run(program)
+            .unwrap()
+    };
+    // Widening sign extends signed types and zero extends unsigned ones
+    assert_eq!(
+        run("funion widen[x: Int8] -> Int (x as Int)\nfunion main[] (widen[255 as Int8])"),
+        -1
+    );
+    assert_eq!(
+        run("funion widen[x: UInt8] -> Int (x as Int)\nfunion main[] (widen[255 as UInt8])"),
+        255
+    );
+    // Narrowing keeps the low bits
+    assert_eq!(
+        run("funion narrow[x: Int] -> Int (x as Int8 as Int)\nfunion main[] (narrow[384])"),
+        -128
+    );
+    assert_eq!(
+        run("funion narrow[x: Int] -> Int (x as UInt8 as Int)\nfunion main[] (narrow[384])"),
+        128
+    );
+    // -2 / 2 is -1, but as a UInt64 it is 2^64 - 2
+    assert_eq!(
+        run("funion half[x: Int] -> Int (x as UInt64 /(2 as UInt64) as Int)\nfunion main[] (half[0 - 2])"),
+        i64::MAX
+    );
+    assert_eq!(
+        run("funion less[x: Int] -> Bool (x as UInt64 <(1 as UInt64))\nfunion main[] (if less[0 - 1] then 1 else 2 end)"),
+        2
+    );
+}
+#[test]
+#[should_panic(expected = "Cannot cast Array(Int) to Int")]
+fn test_invalid_cast() {
+    TypeGenerator::new()
+        .generate_types(parse_program(String::from("funion main[] ({1, 2} as Int)")).unwrap());
+}
+#[test]
+fn test_wrapping_operators() {
+    let funcs = parse_program(String::from("funion main[] (\n  1 +% 2 *% 3\n)"))
+        .unwrap()
+        .funcs;
+    assert_eq!(
+        funcs[0].body,
+        Expr::Operation(
+            Box::new(Expr::Operation(
+                Box::new(Expr::Value(Value::Number(1, "1".to_string()))),
+                Op::WrappingAdd,
+                Box::new(Expr::Value(Value::Number(2, "2".to_string()))),
+                Location { line: 2, column: 5 },
+            )),
+            Op::WrappingMul,
+            Box::new(Expr::Value(Value::Number(3, "3".to_string()))),
+            Location {
+                line: 2,
+                column: 10
+            },
+        )
+    );
+
+    let compile = |source: &str, checked_arithmetic: bool| {
+        let mut program = parse_program(source.to_string()).unwrap();
+        for func in &mut program.funcs {
+            func.file = "panics.wet".to_string();
+        }
+        let program = TypeGenerator::new().generate_types(program);
+        let options = CompilerOptions {
+            checked_arithmetic,
+            ..CompilerOptions::default()
+        };
+        (program, options)
+    };
+    // Wrapping operators wrap even in checked mode
+    let (program, options) = compile(
+        "funion next[x: Int] -> Int (x +% 1)\nfunion main[] (next[9223372036854775807])",
+        true,
+    );
+    assert_eq!(
+        Compiler::<JITModule>::new(options).unwrap().run(program),
+        Ok(i64::MIN)
+    );
+
+    // A panic exits the process, so these run as executables
+    let dir = std::env::temp_dir().join(format!("moistc-panics-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let toolchain = Toolchain {
+        cc: "cc".to_string(),
+        linker: "cc".to_string(),
+    };
+    let overflow = "funion next[x: Int] -> Int (x + 1)\nfunion main[] (next[9223372036854775807])";
+    let divide = |args: &str| {
+        format!("funion div[x: Int, y: Int] -> Int (x / y)\nfunion main[] (div[{args}])")
+    };
+    for (source, checked_arithmetic, panic) in [
+        (
+            overflow.to_string(),
+            true,
+            Some("panic at panics.wet:1:31: arithmetic overflow"),
+        ),
+        (overflow.to_string(), false, None),
+        (
+            divide("1, 0"),
+            false,
+            Some("panic at panics.wet:1:38: division by zero"),
+        ),
+        (
+            divide("0 - 9223372036854775807 - 1, 0 - 1"),
+            false,
+            Some("panic at panics.wet:1:38: arithmetic overflow"),
+        ),
+    ] {
+        let (program, options) = compile(&source, checked_arithmetic);
+        let artifacts = Compiler::<ObjectModule>::new(options)
+            .unwrap()
+            .build(program)
+            .unwrap();
+        let executable = dir.join("panics");
+        link_executable(&artifacts.object, &executable, &toolchain).unwrap();
+        let output = std::process::Command::new(&executable).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match panic {
+            Some(message) => {
+                assert_eq!(output.status.code(), Some(101), "{source}");
+                assert_eq!(stderr.trim_end(), message, "{source}");
+            }
+            None => assert!(output.status.success(), "{source}: {stderr}"),
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn test_nested_array_type() {
+    let funcs = parse_program(String::from(
+        "funion first[xs: Array<Array<Int>>] (xs{0}{1} >> 1)",
+    ))
+    .unwrap()
+    .funcs;
+    assert_eq!(
+        funcs[0].args[0].arg_type,
+        Type::Array(Box::new(Type::Array(Box::new(Type::Int))))
+    );
+}
+#[test]
+#[should_panic(expected = "BitAnd expects integers, got Bool")]
+fn test_bitwise_needs_integers() {
+    TypeGenerator::new().generate_types(
+        parse_program(String::from("funion main[] ((t is true). t & t;)")).unwrap(),
+    );
+}
+#[test]
+fn test_bitwise_operators_run() {
+    // Arguments keep the folder away, so these run the generated code
+    let run = |body: &str, arg: &str| {
+        let source = format!("funion f[x: Int] -> Int ({body})\nfunion main[] (f[{arg}])");
+        let program = TypeGenerator::new().generate_types(parse_program(source).unwrap());
+        Compiler::<JITModule>::new(CompilerOptions::default())
+            .unwrap()
+            .run(program)
+            .unwrap()
+    };
+    assert_eq!(run("x % 10", "0 - 1"), -1);
+    assert_eq!(run("x as UInt64 %(10 as UInt64) as Int", "0 - 1"), 5);
+    assert_eq!(run("x & 10", "12"), 8);
+    assert_eq!(run("x | 10", "12"), 14);
+    assert_eq!(run("x ^ 10", "12"), 6);
+    assert_eq!(run("x << 3", "1"), 8);
+    // Signed values shift in their sign bit, unsigned ones zeros
+    assert_eq!(run("x >> 1", "0 - 8"), -4);
+    assert_eq!(
+        run("x as UInt64 >>(1 as UInt64) as Int", "0 - 8"),
+        i64::MAX - 3
+    );
+}
+#[test]
+fn test_extern_declarations() {
+    let program = TypeGenerator::new().generate_types(
+        parse_program(String::from(
+            "extern funion abs[x: Int32] -> Int32\nextern funion srand[seed: UInt32]\nfunion main[] (srand[abs[5 as Int32] as UInt32])",
+        ))
+        .unwrap(),
+    );
+    assert_eq!(program.externs.len(), 2);
+    assert_eq!(program.externs[0].ret, Some(Type::Int32));
+    assert_eq!(program.externs[1].ret, None);
+    assert_eq!(program.funcs[0].func_type.ret, Type::Int);
+}
+#[test]
+fn test_extern_builtin_clash() {
+    for (name, source) in [
+        ("printint", "funion main[] (printint[1 as Int32])"),
+        ("moist_panic_overflow", "funion main[] (1)"),
+        (
+            "abs",
+            "funion abs[x: Int32] -> Int32 (x)\nfunion main[] (abs[1 as Int32])",
+        ),
+    ] {
+        let program = TypeGenerator::new().generate_types(
+            parse_program(format!("extern funion {name}[x: Int32] -> Int32\n{source}")).unwrap(),
+        );
+        assert_eq!(
+            Compiler::<ObjectModule>::new(CompilerOptions::default())
+                .unwrap()
+                .build(program)
+                .err(),
+            Some(format!(
+                "extern funion {name} clashes with another function of the same name"
+            ))
+        );
+    }
+}
+#[test]
+#[should_panic(expected = "Mismatched number of arguments, abs expects 1 but got 0")]
+fn test_extern_arity() {
+    TypeGenerator::new().generate_types(
+        parse_program(String::from(
+            "extern funion abs[x: Int32] -> Int32\nfunion main[] (abs[])",
+        ))
+        .unwrap(),
+    );
+}
+/// Redeclares every runtime function with the C prototype its registry entry
+/// implies after including `core.c`, so a missing function or a mismatched
+/// signature fails to compile or link.
+#[test]
+fn test_builtins_link_against_runtime() {
+    fn c_type(t: &Type) -> &'static str {
+        match t {
+            Type::Int8 => "int8_t",
+            Type::Int16 => "int16_t",
+            Type::Int32 => "int32_t",
+            Type::UInt8 => "uint8_t",
+            Type::UInt16 => "uint16_t",
+            Type::UInt32 => "uint32_t",
+            Type::UInt64 => "uint64_t",
+            Type::Array(_) => "int64_t *",
+            _ => "int64_t",
+        }
+    }
+
+    let funcs = builtins()
+        .into_iter()
+        .chain(runtime_functions())
+        .collect::<Vec<_>>();
+    let mut stub = format!("#include \"{}/core.c\"\n", env!("CARGO_MANIFEST_DIR"));
+    for func in &funcs {
+        let args = func
+            .args
+            .iter()
+            .map(|arg| c_type(&arg.arg_type))
+            .collect::<Vec<_>>();
+        stub += &format!(
+            "{} {}({});\n",
+            func.ret.as_ref().map_or("void", c_type),
+            func.name,
+            if args.is_empty() {
+                "void".to_string()
+            } else {
+                args.join(", ")
+            }
+        );
+    }
+    stub += "int main(void) {\n\tvoid *used[] = {";
+    for func in &funcs {
+        stub += &format!("(void *){}, ", func.name);
+    }
+    stub += "};\n\treturn used[0] == 0;\n}\n";
+
+    let dir = std::env::temp_dir().join(format!("moistc-builtins-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("stub.c"), stub).unwrap();
+    let output = std::process::Command::new("cc")
+        .arg("stub.c")
+        .arg("-o")
+        .arg("stub")
+        .current_dir(&dir)
+        .output()
+        .expect("Failed to run cc");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+#[test]
+fn test_output_path() {
+    use crate::{output_path, Emit};
+    use std::path::{Path, PathBuf};
+
+    let linux = "x86_64-unknown-linux-gnu".parse().unwrap();
+    let windows = "x86_64-pc-windows-msvc".parse().unwrap();
+    assert_eq!(
+        output_path("examples/hello.wet", Emit::Obj, &linux, None, None),
+        PathBuf::from("hello.o")
+    );
+    assert_eq!(
+        output_path(
+            "hello.wet",
+            Emit::Clif,
+            &linux,
+            None,
+            Some(Path::new("out"))
+        ),
+        PathBuf::from("out/hello.clif")
+    );
+    assert_eq!(
+        output_path(
+            "hello.wet",
+            Emit::Exe,
+            &linux,
+            Some(Path::new("bin/hi")),
+            None
+        ),
+        PathBuf::from("bin/hi")
+    );
+    assert_eq!(
+        output_path("hello.wet", Emit::Exe, &windows, None, None),
+        PathBuf::from("hello.exe")
+    );
+}
+#[test]
+fn test_cross_compile_object_headers() {
+    let object = |target: &str| {
+        let program = TypeGenerator::new()
+            .generate_types(parse_program("funion main[] (printintln[42])".to_string()).unwrap());
+        Compiler::<ObjectModule>::for_target(CompilerOptions::default(), &target.parse().unwrap())
+            .unwrap()
+            .build(program)
+            .unwrap()
+            .object
+    };
+
+    // ELF with e_machine EM_AARCH64
+    let elf = object("aarch64-unknown-linux-gnu");
+    assert_eq!(&elf[..4], b"\x7fELF");
+    assert_eq!(u16::from_le_bytes([elf[18], elf[19]]), 0xB7);
+
+    // COFF with IMAGE_FILE_MACHINE_AMD64
+    let coff = object("x86_64-pc-windows-msvc");
+    assert_eq!(u16::from_le_bytes([coff[0], coff[1]]), 0x8664);
+
+    // 64-bit Mach-O with CPU_TYPE_ARM64
+    let macho = object("aarch64-apple-darwin");
+    assert_eq!(&macho[..4], &0xFEEDFACFu32.to_le_bytes());
+    assert_eq!(&macho[4..8], &0x0100000Cu32.to_le_bytes());
+
+    assert!(Compiler::<ObjectModule>::for_target(
+        CompilerOptions::default(),
+        &"i686-unknown-linux-gnu".parse().unwrap()
+    )
+    .is_err());
+}
+#[test]
+fn test_fmt_round_trip() {
+    let source = "funion main[xs: Array<Array<Int>>] ((n is #xs# as UInt8). each i in xs{0} do printint[i]. printchar[' ']; end. if n >(2 as UInt8) then println[\"big\\t\\\"\"] else println[{'o', 'k'}] end;;)";
+    let formatted = format_program(&parse_program(source.to_string()).unwrap());
+    assert_eq!(
+        formatted,
+        "funion main[xs: Array<Array<Int>>] (
+\t(n is #xs# as UInt8).
+\teach i in xs{0} do
+\t\tprintint[i].
+\t\tprintchar[' ']
+\t\t;
+\tend.
+\tif n >(2 as UInt8) then println[\"big\\t\\\"\"] else println[\"ok\"] end
+\t;;
+)
+"
+    );
+    assert_eq!(
+        format_program(&parse_program(formatted.clone()).unwrap()),
+        formatted
+    );
+
+    // Literals keep the base and separators they were written with
+    let source = "funion main[] (printint[0xFF +(0o17 *(0b1010)) - 1_000_000])";
+    assert_eq!(
+        format_program(&parse_program(source.to_string()).unwrap()),
+        "funion main[] (\n\tprintint[0xFF +(0o17 * 0b1010) - 1_000_000]\n)\n"
+    );
+
+    let source = "funion main[] (each i in(n is 3) do(m is max[i,(n is 2)]) end)";
+    let formatted = format_program(&parse_program(source.to_string()).unwrap());
+    assert_eq!(
+        format_program(&parse_program(formatted.clone()).unwrap()),
+        formatted
+    );
+}
+#[test]
+fn test_dumps() {
+    use crate::{compile, Emit};
+    use target_lexicon::Triple;
+
+    // Every example lexes back to its own text, so no terminal is missing
+    let squeeze = |s: &str| s.split_whitespace().collect::<String>();
+    for entry in std::fs::read_dir("examples").unwrap() {
+        let source = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+        let text: String = tokens(&source)
+            .unwrap()
+            .into_iter()
+            .map(|(_, token)| token)
+            .collect();
+        assert_eq!(squeeze(&text), squeeze(&source));
+    }
+    let first = tokens("funion main[] (\n\tprintintln[1])").unwrap();
+    assert_eq!(first[5], (Location { line: 2, column: 2 }, "printintln"));
+
+    let source = "funion twice[x: Int] -> Int (x * 2) funion main[] (if twice[3] > 5 then {'a', 'b'} else \"no\" end)";
+    let program = TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+    let typed: String = program.funcs.iter().map(format_typed_func).collect();
+    assert_eq!(
+        typed,
+        "funion twice[x: Int] -> Int (
+\t(x: Int * 2): Int
+)
+funion main[] -> Array<Char> (
+\tif (twice[3]: Int > 5): Bool then \"ab\" else \"no\" end: Array<Char>
+)
+"
+    );
+
+    // Stages before the one that fails are still handed out
+    let file = std::env::temp_dir().join(format!("moistc-dumps-{}.wet", std::process::id()));
+    std::fs::write(&file, "funion main[] (1 / 0)").unwrap();
+    let file = file.display().to_string();
+    let mut stages = Vec::new();
+    let options = CompilerOptions::default();
+    let artifacts = compile(&file, options, &Triple::host(), Emit::Ast, |stage, _| {
+        stages.push(stage);
+        Ok(())
+    });
+    assert!(matches!(artifacts, Ok(None)));
+    assert_eq!(stages, [Emit::Tokens, Emit::Ast]);
+    std::fs::remove_file(file).unwrap();
+}
+#[test]
+fn test_modules() {
+    use crate::loader::load;
+
+    let dir = std::env::temp_dir().join(format!("moistc-modules-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    let files = [
+        (
+            "main.wet",
+            "use lib/math\nuse util\n\nfunion helper[] -> Int (\n\t100\n)\nfunion main[] (\n\tutil::twice[math::square[3]] +(helper[])\n)\n",
+        ),
+        (
+            "lib/math.wet",
+            "pub funion square[x: Int] -> Int (\n\tx *(helper[x])\n)\nfunion helper[x: Int] -> Int (\n\tx\n)\n",
+        ),
+        (
+            "util.wet",
+            "use lib/math\n\npub funion twice[x: Int] -> Int (\n\tx *(math::square[1]) * 2\n)\n",
+        ),
+        ("cycle.wet", "use loop\n\nfunion main[] (\n\t1\n)\n"),
+        ("loop.wet", "use cycle\n"),
+        ("private.wet", "use lib/math\n\nfunion main[] (\n\tmath::helper[1]\n)\n"),
+    ];
+    for (name, source) in files {
+        std::fs::write(dir.join(name), source).unwrap();
+        assert_eq!(
+            format_program(&parse_program(source.to_string()).unwrap()),
+            source
+        );
+    }
+    let path = |name: &str| dir.join(name).display().to_string();
+
+    let program = load(&path("main.wet"));
+    let cycle = load(&path("cycle.wet"));
+    let private = load(&path("private.wet"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let program = program.unwrap();
+    let names: Vec<&str> = program
+        .funcs
+        .iter()
+        .map(|func| func.name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "lib::math::square",
+            "lib::math::helper",
+            "util::twice",
+            "helper",
+            "main"
+        ]
+    );
+    let program = TypeGenerator::new().generate_types(program);
+    let value = Compiler::<JITModule>::new(CompilerOptions::default())
+        .unwrap()
+        .run(program)
+        .unwrap();
+    assert_eq!(value, 118);
+
+    assert_eq!(
+        cycle.unwrap_err(),
+        format!(
+            "{}:1:1: Import cycle: {} -> {} -> {}",
+            path("loop.wet"),
+            path("cycle.wet"),
+            path("loop.wet"),
+            path("cycle.wet")
+        )
+    );
+    assert_eq!(
+        private.unwrap_err(),
+        format!("{}:4:2: math has no pub funion helper", path("private.wet"))
+    );
+}
+#[test]
+fn test_time_passes() {
+    use crate::trace::TimePasses;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let timer = TimePasses::default();
+    let subscriber = tracing_subscriber::registry().with(timer.clone());
+    tracing::subscriber::with_default(subscriber, || {
+        let program = TypeGenerator::new().generate_types(
+            parse_program("funion main[] (printintln[1 + 2])".to_string()).unwrap(),
+        );
+        let options = CompilerOptions {
+            opt_level: OptLevel::Speed,
+            ..CompilerOptions::default()
+        };
+        Compiler::<ObjectModule>::new(options)
+            .unwrap()
+            .build(program)
+            .unwrap();
+    });
+    let report = timer.report();
+    let labels: Vec<&str> = report
+        .lines()
+        .map(|line| line.split_once("ms  ").unwrap().1)
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "typecheck",
+            "  function main",
+            "fold",
+            "lower",
+            "  function main",
+            "optimize",
+            "  pass inline",
+            "  pass constant-fold",
+            "  pass hoist-len",
+            "  pass dead-defs",
+            "emit",
+            "  function main",
+            "object",
+        ]
+    );
+}
+#[test]
+fn test_run_propagates_exit_status() {
+    let program = TypeGenerator::new()
+        .generate_types(parse_program(String::from("funion main[] (3 +(4 * 10))")).unwrap());
+    let artifacts = Compiler::<ObjectModule>::new(CompilerOptions::default())
+        .unwrap()
+        .build(program)
+        .unwrap();
+    let toolchain = Toolchain {
+        cc: "cc".to_string(),
+        linker: "cc".to_string(),
+    };
+    let status = run_executable(&artifacts.object, &toolchain, &["ignored".to_string()]).unwrap();
+    assert_eq!(status.code(), Some(43));
+}
+#[test]
+fn test_runtime_has_every_builtin() {
+    let symbols = runtime::symbols();
+    for func in builtins().into_iter().chain(runtime_functions()) {
+        assert!(
+            symbols.iter().any(|(name, _)| *name == func.name),
+            "{} is missing from the JIT runtime",
+            func.name
+        );
+    }
+}
+#[test]
+fn test_jit_runs_in_process() {
+    let program = TypeGenerator::new().generate_types(
+        parse_program(String::from(
+            "funion double[x: Int8] (x *%(2 as Int8))\nfunion main[] (printint[7]. double[100 as Int8];)",
+        ))
+        .unwrap(),
+    );
+    assert_eq!(
+        Compiler::<JITModule>::new(CompilerOptions::default())
+            .unwrap()
+            .run(program)
+            .unwrap(),
+        -56
+    );
+
+    let run = |source: &str| {
+        let program =
+            TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+        Compiler::<JITModule>::new(CompilerOptions::default())
+            .unwrap()
+            .run(program)
+    };
+    assert_eq!(
+        run("funion start[] (1)"),
+        Err("Program has no main function".to_string())
+    );
+    assert_eq!(
+        run("funion main[x: Int] (x)"),
+        Err("main can't take arguments when it is run in memory".to_string())
+    );
+}
+#[test]
+fn test_repl_multi_line_input() {
+    use crate::repl::is_incomplete;
+
+    assert!(!is_incomplete("1 + 2\n"));
+    assert!(is_incomplete("funion add[a: Int, b: Int] (\n"));
+    assert!(is_incomplete("each c in \"ab\" do\n"));
+    assert!(!is_incomplete("each c in \"(end\" do printchar[')'] end\n"));
+    assert!(is_incomplete("if x >(2) then\n"));
+}
+#[test]
+fn test_repl_keyword_prefixes() {
+    use crate::repl::Session;
+
+    let mut session = Session::new(crate::prelude(), CompilerOptions::default()).unwrap();
+    assert_eq!(session.evaluate("funion useful[] -> Int (3)\n"), Ok(None));
+    // Starts like `use` but is a call
+    assert_eq!(
+        session.evaluate("useful[] + 1\n"),
+        Ok(Some("4: Int".to_string()))
+    );
+    assert_eq!(
+        session.evaluate("use lib/math\n"),
+        Err("use only works in files, not in the repl".to_string())
+    );
+}
+/// Runs every example through the interpreter and as a compiled executable,
+/// and checks both print the same thing and exit with the same status.
+#[test]
+fn test_interpreter_matches_compiled_examples() {
+    let mut examples = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/examples"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    examples.sort();
+    assert!(!examples.is_empty());
+
+    let dir = std::env::temp_dir().join(format!("moistc-examples-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for example in examples {
+        let program =
+            TypeGenerator::new().generate_types(crate::load(example.to_str().unwrap()).unwrap());
+
+        let mut output = Vec::new();
+        let status = match Interpreter::new(&program.funcs, false, &mut &b""[..], &mut output).run()
+        {
+            Ok(value) => value as u8 as i32,
+            Err(Stop::Panic(_)) => 101,
+            Err(Stop::Unsupported(message)) => panic!("{}: {message}", example.display()),
+        };
+
+        // Optimizations mustn't change what a program does either
+        for opt_level in [OptLevel::None, OptLevel::Speed] {
+            let executable = dir.join(example.file_stem().unwrap());
+            let options = CompilerOptions {
+                opt_level,
+                ..CompilerOptions::default()
+            };
+            let artifacts = Compiler::<ObjectModule>::new(options)
+                .unwrap()
+                .build(program.clone())
+                .unwrap();
+            let toolchain = Toolchain {
+                cc: "cc".to_string(),
+                linker: "cc".to_string(),
+            };
+            link_executable(&artifacts.object, &executable, &toolchain).unwrap();
+            let compiled = std::process::Command::new(&executable)
+                .stdin(std::process::Stdio::null())
+                .output()
+                .unwrap();
+
+            assert_eq!(
+                String::from_utf8_lossy(&output),
+                String::from_utf8_lossy(&compiled.stdout),
+                "{} printed something else when compiled at {opt_level:?}",
+                example.display()
+            );
+            assert_eq!(
+                Some(status),
+                compiled.status.code(),
+                "{} exited differently when compiled at {opt_level:?}",
+                example.display()
+            );
+        }
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+#[test]
+fn test_opt_levels_agree() {
+    let source =
+        "funion main[] ((total is 0). each i in 10 do(total is total +(i * i)) end. total / 5;;)";
+    for opt_level in [OptLevel::None, OptLevel::Speed, OptLevel::SpeedAndSize] {
+        let program =
+            TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+        let options = CompilerOptions {
+            opt_level,
+            ..CompilerOptions::default()
+        };
+        assert_eq!(
+            Compiler::<JITModule>::new(options)
+                .unwrap()
+                .run(program)
+                .unwrap(),
+            77
+        );
+    }
+
+    let options = CompilerOptions {
+        cranelift_flags: vec![("no_such_flag".to_string(), "true".to_string())],
+        ..CompilerOptions::default()
+    };
+    assert!(Compiler::<JITModule>::new(options).is_err());
+}
+#[test]
+fn test_mir_passes() {
+    let source = "funion five[] (2 + 3)
+funion sum[xs: Array<Int>] ((total is 0). each i in 3 do(total is total +(#xs#)) end. total;;)
+funion main[] ((unused is 1). five[] +(sum[{1, 2}]);)";
+    let program = TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+    let options = CompilerOptions {
+        opt_level: OptLevel::Speed,
+        ..CompilerOptions::default()
+    };
+    let mir = Compiler::<ObjectModule>::new(options)
+        .unwrap()
+        .build(program)
+        .unwrap()
+        .mir;
+
+    // Both calls are inlined, five[] is folded and unused is gone
+    assert!(mir.contains("\t%4: Int = 5 +% %3\n"), "{mir}");
+    assert!(!mir.contains("= sum["), "{mir}");
+    assert!(!mir.contains("unused"), "{mir}");
+    // The length of xs is read once, before the loop
+    let hoisted = mir.find("#%").unwrap();
+    assert!(hoisted < mir.find("each").unwrap(), "{mir}");
+}
+#[test]
+fn test_fold_constants() {
+    use crate::{compiler::fold::fold_program, loader::parse_file};
+
+    let compile = |source: &str, checked_arithmetic: bool| {
+        let program = TypeGenerator::new()
+            .generate_types(parse_file("main.wet", source.to_string()).unwrap());
+        let options = CompilerOptions {
+            checked_arithmetic,
+            ..CompilerOptions::default()
+        };
+        Compiler::<ObjectModule>::new(options)
+            .unwrap()
+            .build(program)
+            .map(|artifacts| artifacts.mir)
+    };
+
+    let mir = compile(
+        "funion main[] (printintln[2 * 60 * 60]. printintln[#\"hello\"#]. if true then 1 else 1 / 0 end;;)",
+        false,
+    )
+    .unwrap();
+    assert!(mir.contains("printintln[7200]"), "{mir}");
+    assert!(mir.contains("printintln[5]"), "{mir}");
+    assert!(mir.contains("return 1\n"), "{mir}");
+
+    assert_eq!(
+        compile("funion main[] (1 /(2 - 2))", false).unwrap_err(),
+        "main.wet:1:18: division by zero"
+    );
+    // Overflow only panics with checked arithmetic
+    let overflow = "funion main[] (127 as Int8 +(1 as Int8) as Int)";
+    assert!(compile(overflow, false).is_ok());
+    assert_eq!(
+        compile(overflow, true).unwrap_err(),
+        "main.wet:1:28: arithmetic overflow"
+    );
+    // UInt64 products are checked at their own width
+    let product = "funion main[] (0 - 1 as UInt64 *(0 - 1 as UInt64) as Int)";
+    assert!(compile(product, false).is_ok());
+    assert_eq!(
+        compile(product, true).unwrap_err(),
+        "main.wet:1:32: arithmetic overflow"
+    );
+    let program = TypeGenerator::new().generate_types(parse_program(product.to_string()).unwrap());
+    let result = Interpreter::new(&program.funcs, false, &mut &b""[..], &mut Vec::new()).run();
+    assert_eq!(result.unwrap(), 1);
+
+    // Calls in an array literal still happen when its length is known
+    let program = TypeGenerator::new()
+        .generate_types(parse_program("funion main[] (#{printint[1]}#)".to_string()).unwrap());
+    let folded = fold_program(program, false).unwrap();
+    assert!(matches!(folded.funcs[0].body, TypedExpr::Len(_)));
+}
+#[test]
+fn test_tail_calls_run_in_constant_stack() {
+    let source =
+        "funion count[n: Int, acc: Int] -> Int (if n = 0 then acc else count[n - 1, acc + 1] end)
+funion even[n: Int] -> Bool (if n = 0 then true else odd[n - 1] end)
+funion odd[n: Int] -> Bool (if n = 0 then false else even[n - 1] end)
+funion main[] (if even[1000001] then 0 else count[10000000, 0] end)";
+    for opt_level in [OptLevel::None, OptLevel::Speed] {
+        let program =
+            TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+        let options = CompilerOptions {
+            opt_level,
+            ..CompilerOptions::default()
+        };
+        assert_eq!(
+            Compiler::<JITModule>::new(options)
+                .unwrap()
+                .run(program)
+                .unwrap(),
+            10000000
+        );
+    }
+}
+#[test]
+fn test_each_counts_from_redefined_variable() {
+    let source = "funion main[] ((total is 0). each i in 10 do(i is i + 2).(total is total + i); end. total;;)";
+    let program = TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+    let interpreted = Interpreter::new(&program.funcs, false, &mut &b""[..], &mut Vec::new()).run();
+    // i is 1, 4, 7 and 10 at the start of each iteration
+    assert_eq!(interpreted.unwrap(), 3 + 6 + 9 + 12);
+    assert_eq!(
+        Compiler::<JITModule>::new(CompilerOptions::default())
+            .unwrap()
+            .run(program),
+        Ok(30)
+    );
+}
+#[test]
+fn test_tail_calls_keep_stack_arrays_alive() {
+    let sources = [
+        // The callee overwrites the caller's frame before reading the array
+        "funion clobber[a: Int, b: Int, c: Int] -> Int ((arr is {a, b, c}). arr{0} +(arr{2});)
+funion first[xs: Array<Int>] -> Int ((z is clobber[7, 7, 7]). xs{0};)
+funion wrap[n: Int] -> Int (first[{n, n, n}])
+funion main[] (wrap[42])",
+        // Each iteration rebuilds ys where the previous one is still read
+        "funion loop[xs: Array<Int>, n: Int, total: Int] -> Int (
+    (ys is {n}). if n = 0 then total else loop[ys, n - 1, total +(xs{0})] end;
+)
+funion main[] (loop[{100}, 3, 2])",
+    ];
+    for source in sources {
+        let program =
+            TypeGenerator::new().generate_types(parse_program(source.to_string()).unwrap());
+        let expected = Interpreter::new(&program.funcs, false, &mut &b""[..], &mut Vec::new())
+            .run()
+            .unwrap();
+        for opt_level in [OptLevel::None, OptLevel::Speed] {
+            let options = CompilerOptions {
+                opt_level,
+                ..CompilerOptions::default()
+            };
+            assert_eq!(
+                Compiler::<JITModule>::new(options)
+                    .unwrap()
+                    .run(program.clone()),
+                Ok(expected),
+                "{source} at {opt_level:?}"
+            );
+        }
+    }
+}
+#[test]
+fn test_debug_info_sections() {
+    use cranelift_object::object::{read::elf::ElfFile64, Endianness, Object, ObjectSection};
+
+    let options = CompilerOptions {
+        debug_info: true,
+        ..CompilerOptions::default()
+    };
+    let mut program = parse_program(
+        "funion answer[x: Int] (x * 7)\nfunion main[] (printintln[answer[6]])".to_string(),
+    )
+    .unwrap();
+    for func in &mut program.funcs {
+        func.file = "answer.wet".to_string();
+    }
+    let mut with_prelude = crate::prelude();
+    with_prelude.extend(program);
+    let program = TypeGenerator::new().generate_types(with_prelude);
+    let object = Compiler::<ObjectModule>::for_target(
+        options.clone(),
+        &"x86_64-unknown-linux-gnu".parse().unwrap(),
+    )
+    .unwrap()
+    .build(program)
+    .unwrap()
+    .object;
+
+    let elf = ElfFile64::<Endianness>::parse(&*object).unwrap();
+    for name in [
+        ".debug_info",
+        ".debug_abbrev",
+        ".debug_line",
+        ".debug_frame",
+        ".debug_str",
+    ] {
+        let section = elf
+            .section_by_name(name)
+            .unwrap_or_else(|| panic!("no {name}"));
+        assert!(!section.data().unwrap().is_empty(), "{name} is empty");
+    }
+    let line = elf.section_by_name(".debug_line").unwrap();
+    assert!(line
+        .data()
+        .unwrap()
+        .windows(10)
+        .any(|name| name == b"answer.wet"));
+    // The prelude is embedded, so it points at its source instead
+    assert!(std::path::Path::new(crate::PRELUDE).exists());
+    assert!(line
+        .data()
+        .unwrap()
+        .windows(crate::PRELUDE.len())
+        .any(|name| name == crate::PRELUDE.as_bytes()));
+    let strings = elf.section_by_name(".debug_str").unwrap();
+    assert!(strings
+        .data()
+        .unwrap()
+        .windows(7)
+        .any(|name| name == b"answer\0"));
+
+    assert!(Compiler::<ObjectModule>::for_target(
+        options,
+        &"aarch64-apple-darwin".parse().unwrap()
+    )
+    .is_err());
+}
@@ -0,0 +1,118 @@
+use std::{
+    fmt::{self, Write as _},
+    io::{self, IsTerminal},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+/// Environment variable holding the filter for log output, like
+/// `MOISTC_LOG=debug` or `MOISTC_LOG=moistc::compiler=trace`.
+const LOG_VAR: &str = "MOISTC_LOG";
+
+/// Installs the logger if `MOISTC_LOG` is set and the timer if
+/// `time_passes` is. Without either nothing is installed, so the spans cost
+/// next to nothing.
+pub fn init(time_passes: bool) -> Option<TimePasses> {
+    let log = std::env::var_os(LOG_VAR).map(|_| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .with_ansi(io::stderr().is_terminal())
+            .with_filter(EnvFilter::from_env(LOG_VAR))
+    });
+    let timer = time_passes.then(TimePasses::default);
+    if log.is_some() || timer.is_some() {
+        tracing_subscriber::registry()
+            .with(log)
+            .with(timer.clone())
+            .init();
+    }
+    timer
+}
+
+/// Times every span from when it is created until it closes.
+#[derive(Clone, Default)]
+pub struct TimePasses {
+    timings: Arc<Mutex<Vec<Timing>>>,
+}
+
+struct Timing {
+    label: String,
+    depth: usize,
+    elapsed: Duration,
+}
+
+/// Where a span's timing is kept and when it started.
+struct Started {
+    index: usize,
+    at: Instant,
+}
+
+impl TimePasses {
+    /// One line per span in the order they started, nested spans indented
+    /// below the one they ran in.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for timing in self.timings.lock().unwrap().iter() {
+            writeln!(
+                report,
+                "{:>10.3}ms  {}{}",
+                timing.elapsed.as_secs_f64() * 1000.0,
+                "  ".repeat(timing.depth),
+                timing.label
+            )
+            .unwrap();
+        }
+        report
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TimePasses {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("new span is registered");
+        let mut label = Label(attrs.metadata().name().to_string());
+        attrs.record(&mut label);
+
+        let mut timings = self.timings.lock().unwrap();
+        timings.push(Timing {
+            label: label.0,
+            depth: span.scope().skip(1).count(),
+            elapsed: Duration::ZERO,
+        });
+        span.extensions_mut().insert(Started {
+            index: timings.len() - 1,
+            at: Instant::now(),
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).expect("closed span is registered");
+        let extensions = span.extensions();
+        if let Some(started) = extensions.get::<Started>() {
+            self.timings.lock().unwrap()[started.index].elapsed = started.at.elapsed();
+        }
+    }
+}
+
+/// A span's name followed by the values of its fields.
+struct Label(String);
+
+impl Visit for Label {
+    fn record_str(&mut self, _field: &Field, value: &str) {
+        write!(self.0, " {value}").unwrap();
+    }
+
+    fn record_debug(&mut self, _field: &Field, value: &dyn fmt::Debug) {
+        write!(self.0, " {value:?}").unwrap();
+    }
+}